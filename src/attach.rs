@@ -0,0 +1,270 @@
+use anyhow::{Context, Result};
+use std::env;
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Files attached to a prompt with @path or --file, and input piped to wtf
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// The model sees a bounded excerpt of each, enough to know its format and what
+// its lines look like, never the whole thing.
+
+/// Bytes taken from the start of a file that is too big to include whole.
+const HEAD_BYTES: usize = 2000;
+/// Bytes taken from its end.
+const TAIL_BYTES: usize = 2000;
+/// How much is sniffed for NUL bytes and broken UTF-8 to spot binary files.
+const SNIFF_BYTES: usize = 8000;
+/// Piped input is read up to this size, so `yes | wtf …` still returns.
+const STDIN_LIMIT: usize = 1_000_000;
+/// How long a pipe gets to show it has input when nobody is at the terminal. Under
+/// cron, CI or `ssh host wtf …`, stdin can be a pipe that stays open and empty.
+const STDIN_WAIT: Duration = Duration::from_millis(500);
+
+pub struct Attachment {
+    /// As the user wrote it, relative paths and all
+    pub name: String,
+    pub size: u64,
+    excerpt: String,
+    truncated: bool,
+    /// Read from stdin rather than a file
+    piped: bool,
+}
+
+/// Whatever was piped into wtf.
+pub struct Piped {
+    data: Vec<u8>,
+    /// Whether there was more than `STDIN_LIMIT`
+    cut_off: bool,
+}
+
+/// Read piped input, up to `STDIN_LIMIT` bytes. `None` when stdin is a terminal or empty,
+/// or when nothing is being piped in.
+pub fn read_stdin() -> Result<Option<Piped>> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() || !has_input(&stdin) {
+        return Ok(None);
+    }
+    let mut data = Vec::new();
+    stdin
+        .lock()
+        .take(STDIN_LIMIT as u64 + 1)
+        .read_to_end(&mut data)
+        .context("Failed to read stdin")?;
+    let cut_off = data.len() > STDIN_LIMIT;
+    data.truncate(STDIN_LIMIT);
+    Ok((!data.iter().all(u8::is_ascii_whitespace)).then_some(Piped { data, cut_off }))
+}
+
+/// Whether stdin is a file, or a pipe that is being written to. Someone at a terminal
+/// piping a slow command into wtf gets waited for; otherwise the pipe has `STDIN_WAIT`
+/// to have data or be closed.
+#[cfg(unix)]
+fn has_input(stdin: &io::Stdin) -> bool {
+    use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+    use nix::sys::stat::{fstat, SFlag};
+    use std::os::fd::AsFd;
+
+    let Ok(stat) = fstat(stdin.as_fd()) else {
+        return false;
+    };
+    let kind = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT;
+    if kind == SFlag::S_IFREG {
+        return true;
+    }
+    if kind != SFlag::S_IFIFO && kind != SFlag::S_IFSOCK {
+        return false;
+    }
+    if io::stderr().is_terminal() {
+        return true;
+    }
+    let mut fds = [PollFd::new(stdin.as_fd(), PollFlags::POLLIN)];
+    let timeout = PollTimeout::try_from(STDIN_WAIT).unwrap_or(PollTimeout::MAX);
+    matches!(poll(&mut fds, timeout), Ok(ready) if ready > 0)
+}
+
+#[cfg(not(unix))]
+fn has_input(_stdin: &io::Stdin) -> bool {
+    true
+}
+
+impl Piped {
+    /// Use the input as the prompt itself (`echo "list open ports" | wtf`).
+    pub fn into_prompt(self) -> String {
+        String::from_utf8_lossy(&self.data).trim().to_string()
+    }
+
+    fn into_attachment(self) -> Result<Attachment> {
+        let sniff = &self.data[..SNIFF_BYTES.min(self.data.len())];
+        if is_binary(sniff) {
+            anyhow::bail!("The input piped to wtf is binary; only text can be attached");
+        }
+        let size = self.data.len();
+        let whole = size <= HEAD_BYTES + TAIL_BYTES && !self.cut_off;
+        let excerpt = if whole {
+            String::from_utf8_lossy(&self.data).into_owned()
+        } else {
+            let head = &self.data[..HEAD_BYTES.min(size)];
+            let tail = &self.data[size.saturating_sub(TAIL_BYTES).max(head.len())..];
+            cut(head, tail, size as u64)
+        };
+        Ok(Attachment {
+            name: "stdin".to_string(),
+            size: size as u64,
+            excerpt,
+            truncated: !whole,
+            piped: true,
+        })
+    }
+}
+
+impl Attachment {
+    fn read(name: &str) -> Result<Self> {
+        let path = expand_home(name);
+        let mut file = File::open(&path).with_context(|| format!("Cannot read {}", name))?;
+        let meta = file.metadata().with_context(|| format!("Cannot read {}", name))?;
+        if meta.is_dir() {
+            anyhow::bail!("{} is a directory, not a file", name);
+        }
+        let size = meta.len();
+
+        let mut sniff = vec![0; SNIFF_BYTES.min(size as usize)];
+        file.read_exact(&mut sniff).with_context(|| format!("Cannot read {}", name))?;
+        if is_binary(&sniff) {
+            anyhow::bail!("{} is a binary file; only text files can be attached", name);
+        }
+
+        let whole = size as usize <= HEAD_BYTES + TAIL_BYTES;
+        let excerpt = if whole {
+            String::from_utf8_lossy(&sniff).into_owned()
+        } else {
+            let mut tail = vec![0; TAIL_BYTES];
+            file.seek(SeekFrom::End(-(TAIL_BYTES as i64)))
+                .and_then(|_| file.read_exact(&mut tail))
+                .with_context(|| format!("Cannot read {}", name))?;
+            cut(&sniff[..HEAD_BYTES], &tail, size)
+        };
+
+        Ok(Attachment {
+            name: name.to_string(),
+            size,
+            excerpt,
+            truncated: !whole,
+            piped: false,
+        })
+    }
+
+    /// The size of what is sent, for `--verbose`.
+    pub fn summary(&self) -> String {
+        if self.truncated {
+            format!(
+                "{}: first and last lines, {} of {}",
+                self.name,
+                format_size(self.excerpt.len() as u64),
+                format_size(self.size)
+            )
+        } else {
+            format!("{}: all {}", self.name, format_size(self.size))
+        }
+    }
+}
+
+/// The prompt to send with `@path` references resolved, followed by excerpts of
+/// those files and of `files`.
+///
+/// `@word` that isn't an existing path (`npm i @types/node`) is left alone; a file
+/// that can't be read, a directory or a binary file is an error.
+pub fn attach(prompt: &str, files: &[PathBuf], piped: Option<Piped>) -> Result<(String, Vec<Attachment>)> {
+    let mut attachments = Vec::new();
+    if let Some(piped) = piped {
+        attachments.push(piped.into_attachment()?);
+    }
+    for file in files {
+        attachments.push(Attachment::read(&file.to_string_lossy())?);
+    }
+
+    let mut text = String::with_capacity(prompt.len());
+    for (index, token) in prompt.split(' ').enumerate() {
+        if index > 0 {
+            text.push(' ');
+        }
+        let Some(reference) = token.strip_prefix('@') else {
+            text.push_str(token);
+            continue;
+        };
+        // "look at @notes.txt." ends the sentence, not the path
+        let name = reference.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '"', '\'']);
+        if name.is_empty() || !expand_home(name).exists() {
+            text.push_str(token);
+            continue;
+        }
+        if !attachments.iter().any(|a| a.name == name) {
+            attachments.push(Attachment::read(name)?);
+        }
+        text.push_str(reference);
+    }
+
+    if attachments.is_empty() {
+        return Ok((prompt.to_string(), attachments));
+    }
+
+    for (index, attachment) in attachments.iter().enumerate() {
+        if attachment.piped {
+            text.push_str(
+                "\n\nThe user piped this into the request. The command won't receive it on stdin, \
+                 so use it to work out the names and values the command needs:\n",
+            );
+        } else if index == 0 || attachments[index - 1].piped {
+            text.push_str("\n\nExcerpts of the files mentioned:\n");
+        }
+        let note = if attachment.truncated { ", first and last lines only" } else { "" };
+        text.push_str(&format!(
+            "\n--- {} ({}{}) ---\n{}",
+            attachment.name,
+            format_size(attachment.size),
+            note,
+            attachment.excerpt
+        ));
+        if !attachment.excerpt.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str("--- end ---\n");
+    }
+    Ok((text, attachments))
+}
+
+/// The start and end of something too big to send whole, cut at line breaks.
+fn cut(head: &[u8], tail: &[u8], size: u64) -> String {
+    let head = String::from_utf8_lossy(head);
+    // Whole lines only, unless one line fills it all
+    let head = head.rfind('\n').map_or(&*head, |end| &head[..=end]);
+    let tail = String::from_utf8_lossy(tail);
+    let tail = tail.find('\n').map_or(&*tail, |start| &tail[start + 1..]);
+
+    let omitted = size.saturating_sub((head.len() + tail.len()) as u64);
+    format!("{}[… {} omitted …]\n{}", head, format_size(omitted), tail)
+}
+
+fn expand_home(name: &str) -> PathBuf {
+    match (name.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(name),
+    }
+}
+
+/// NUL bytes or invalid UTF-8 (other than a character cut off at the end).
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes.contains(&0)
+        || std::str::from_utf8(bytes).is_err_and(|e| e.error_len().is_some())
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1000 => format!("{} B", bytes),
+        1000..1_000_000 => format!("{:.1} KB", bytes as f64 / 1000.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_000_000.0),
+    }
+}
@@ -0,0 +1,546 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::lint;
+use crate::retry::RetryPolicy;
+
+const DEFAULT_MAX_TOKENS: u32 = 500;
+const DEFAULT_AZURE_API_VERSION: &str = "2024-06-01";
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_SYNTAX_REPAIRS: u32 = 2;
+const DEFAULT_FIX_ATTEMPTS: u32 = 2;
+const DEFAULT_HISTORY_TOKENS: u32 = 3000;
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Config file (~/.config/wtf/config.toml)
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Example:
+//
+//   default_profile = "gemini"
+//
+//   [profiles.gemini]
+//   provider = "gemini"
+//   api_key_env = "GEMINI_API_KEY"
+//   fallback = ["local"]
+//
+//   [profiles.gateway]
+//   provider = "openai"
+//   base_url = "https://llm-gateway.internal/v1"
+//   model = "gpt-4o-mini"
+//   api_key_cmd = "pass show llm-gateway"
+//   temperature = 0.2
+//   stream = false
+//   response_schema = false
+//   max_retries = 5
+//   timeout_secs = 30
+//
+//   [profiles.azure]
+//   provider = "azure"
+//   base_url = "https://my-resource.openai.azure.com"
+//   deployment = "gpt-4o-mini"
+//   api_version = "2024-06-01"
+//   api_key_env = "AZURE_OPENAI_API_KEY"
+//
+//   [profiles.local]
+//   provider = "ollama"
+//   model = "qwen2.5-coder:7b"
+//   keep_alive = "30m"
+//   num_ctx = 8192
+//
+//   [checks]
+//   missing_tools = "retry"
+//   syntax_repairs = 3
+//
+//   [checks.lint]
+//   SC2002 = false
+//
+//   [context]
+//   workspace = true
+//
+//   [interactive]
+//   fix_attempts = 3
+//   history_tokens = 6000
+
+/// The whole file, read once per run; profiles are resolved with `Config::load_chain`.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub checks: Checks,
+    #[serde(default)]
+    pub context: ContextSettings,
+    #[serde(default)]
+    pub interactive: InteractiveSettings,
+}
+
+impl ConfigFile {
+    /// Read the config file, or defaults when there is none.
+    pub fn load() -> Result<Self> {
+        let Some(path) = config_path() else {
+            return Ok(ConfigFile::default());
+        };
+        if !path.exists() {
+            return Ok(ConfigFile::default());
+        }
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let file: ConfigFile =
+            toml::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?;
+        file.checks.validate()?;
+        Ok(file)
+    }
+}
+
+/// What to verify in generated commands, independent of the profile in use.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Checks {
+    /// What to do when a command runs programs that are not installed
+    pub missing_tools: MissingTools,
+    /// How many times to send a syntax error back to the model for a fix (0 to only report it)
+    pub syntax_repairs: u32,
+    /// Lint rules by ID, e.g. `SC2086 = false`; rules not listed are on
+    pub lint: BTreeMap<String, bool>,
+}
+
+impl Default for Checks {
+    fn default() -> Self {
+        Checks {
+            missing_tools: MissingTools::default(),
+            syntax_repairs: DEFAULT_SYNTAX_REPAIRS,
+            lint: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingTools {
+    /// Point them out below the command
+    #[default]
+    Warn,
+    /// Ask the model once more for a command that avoids them
+    Retry,
+    Off,
+}
+
+impl Checks {
+    fn validate(&self) -> Result<()> {
+        if let Some(unknown) = self.lint.keys().find(|id| !lint::RULES.iter().any(|rule| rule.id == *id)) {
+            let known: Vec<&str> = lint::RULES.iter().map(|rule| rule.id).collect();
+            anyhow::bail!("Unknown lint rule '{}' in [checks.lint] (known: {})", unknown, known.join(", "));
+        }
+        Ok(())
+    }
+
+    pub fn lint_enabled(&self, rule: &str) -> bool {
+        self.lint.get(rule).copied().unwrap_or(true)
+    }
+}
+
+/// What the prompt tells the model about where the command will run.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContextSettings {
+    /// OS, shell, userland and installed tools (default: true)
+    pub system: bool,
+    /// Files in the current directory, project type and git state (default: false)
+    pub workspace: bool,
+}
+
+impl Default for ContextSettings {
+    fn default() -> Self {
+        ContextSettings {
+            system: true,
+            workspace: false,
+        }
+    }
+}
+
+/// How `wtf -i` behaves.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InteractiveSettings {
+    /// How many times a command that fails when run can be sent back to the model for a fix (0 to never offer it)
+    pub fix_attempts: u32,
+    /// Roughly how many tokens of the conversation go with each request (0 for none)
+    pub history_tokens: u32,
+}
+
+impl Default for InteractiveSettings {
+    fn default() -> Self {
+        InteractiveSettings {
+            fix_attempts: DEFAULT_FIX_ATTEMPTS,
+            history_tokens: DEFAULT_HISTORY_TOKENS,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    provider: Option<Provider>,
+    base_url: Option<String>,
+    model: Option<String>,
+    /// Literal key. Prefer `api_key_env` or `api_key_cmd` so secrets stay out of the file.
+    api_key: Option<String>,
+    /// Name of an environment variable holding the key
+    api_key_env: Option<String>,
+    /// Shell command whose stdout is the key (e.g. `pass show gemini`)
+    api_key_cmd: Option<String>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    /// Azure OpenAI deployment name (defaults to the model name)
+    deployment: Option<String>,
+    /// Azure OpenAI `api-version` query parameter
+    api_version: Option<String>,
+    /// How long Ollama keeps the model loaded after a request (e.g. "30m")
+    keep_alive: Option<String>,
+    /// Ollama context window size (`options.num_ctx`)
+    num_ctx: Option<u32>,
+    /// Stream responses as they are generated (default: true)
+    stream: Option<bool>,
+    /// Enforce the JSON reply format with the provider's schema support (default: true)
+    response_schema: Option<bool>,
+    /// Retries on rate limits (429) and server errors (default: 3)
+    max_retries: Option<u32>,
+    /// Upper bound on total time spent on a request, retries included (default: 60)
+    timeout_secs: Option<u64>,
+    /// Profiles to try, in order, when this one is down or rate limited
+    #[serde(default)]
+    fallback: Vec<String>,
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Resolved configuration
+// ─────────────────────────────────────────────────────────────────────────────
+
+pub struct Config {
+    /// Profile name, or the provider name when no profile is in use
+    pub name: String,
+    pub api_key: String,
+    pub base_url: String,
+    pub model: String,
+    pub provider: Provider,
+    pub temperature: Option<f32>,
+    pub max_tokens: u32,
+    pub stream: bool,
+    pub response_schema: bool,
+    pub retry: RetryPolicy,
+    pub azure: Option<AzureConfig>,
+    pub ollama: Option<OllamaConfig>,
+}
+
+pub struct AzureConfig {
+    pub deployment: String,
+    pub api_version: String,
+}
+
+pub struct OllamaConfig {
+    pub keep_alive: Option<String>,
+    pub num_ctx: Option<u32>,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Gemini,
+    OpenAI, // OpenAI-compatible (OpenRouter, Azure, Ollama, etc.)
+    Anthropic,
+    Azure,  // Azure OpenAI deployments
+    Ollama, // Native Ollama API (/api/chat)
+}
+
+impl Provider {
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::Gemini => "gemini",
+            Provider::OpenAI => "openai",
+            Provider::Anthropic => "anthropic",
+            Provider::Azure => "azure",
+            Provider::Ollama => "ollama",
+        }
+    }
+
+    fn default_base_url(self) -> Option<&'static str> {
+        match self {
+            Provider::Gemini => Some("https://generativelanguage.googleapis.com/v1beta"),
+            Provider::OpenAI => Some("https://api.openai.com/v1"),
+            Provider::Anthropic => Some("https://api.anthropic.com/v1"),
+            Provider::Ollama => Some("http://localhost:11434"),
+            // Every Azure resource has its own endpoint
+            Provider::Azure => None,
+        }
+    }
+
+    fn default_model(self) -> &'static str {
+        match self {
+            Provider::Gemini => "gemini-2.0-flash",
+            Provider::OpenAI | Provider::Azure => "gpt-4o-mini",
+            Provider::Anthropic => "claude-3-5-haiku-latest",
+            Provider::Ollama => "llama3.2",
+        }
+    }
+}
+
+/// The active configuration, then the profiles to fall back on. A fallback is only
+/// resolved when it is needed, since that runs its `api_key_cmd`, and then only once.
+pub struct ConfigChain<'a> {
+    file: &'a ConfigFile,
+    primary: Config,
+    fallback: Vec<(String, OnceCell<Option<Config>>)>,
+}
+
+impl ConfigChain<'_> {
+    pub fn primary(&self) -> &Config {
+        &self.primary
+    }
+
+    /// The fallback profiles in order, resolving each as the iterator gets to it.
+    /// Broken ones are skipped with a warning.
+    pub fn fallbacks(&self) -> impl Iterator<Item = &Config> {
+        self.fallback.iter().filter_map(|(name, config)| {
+            config
+                .get_or_init(|| match Config::from_profile(self.file, Some(name), false) {
+                    Ok((config, _)) => Some(config),
+                    Err(e) => {
+                        eprintln!(
+                            "\r\x1b[K\x1b[33mWarning: skipping fallback profile '{}': {}\x1b[0m",
+                            name,
+                            e.to_string().lines().next().unwrap_or_default()
+                        );
+                        None
+                    }
+                })
+                .as_ref()
+        })
+    }
+}
+
+impl Config {
+    /// Resolve the active configuration and name its fallback profiles.
+    ///
+    /// The profile is picked from `--profile`, then `WTF_PROFILE`, then `default_profile`
+    /// in the config file. Environment variables (`WTF_API_KEY`, `WTF_BASE_URL`,
+    /// `WTF_MODEL`, `WTF_PROVIDER`) override whatever the active profile says; fallback
+    /// profiles are used exactly as written in the file, except that a Gemini one
+    /// without a key still takes `GEMINI_API_KEY`.
+    pub fn load_chain<'a>(file: &'a ConfigFile, profile: Option<&str>) -> Result<ConfigChain<'a>> {
+        let profile_name = profile
+            .map(str::to_string)
+            .or_else(|| env::var("WTF_PROFILE").ok().filter(|p| !p.is_empty()))
+            .or_else(|| file.default_profile.clone());
+
+        let (primary, fallback) = Self::from_profile(file, profile_name.as_deref(), true)?;
+        Ok(ConfigChain {
+            file,
+            primary,
+            fallback: fallback.into_iter().map(|name| (name, OnceCell::new())).collect(),
+        })
+    }
+
+    /// Build one config from a profile, returning it with the profile's fallback list.
+    fn from_profile(file: &ConfigFile, profile_name: Option<&str>, use_env: bool) -> Result<(Self, Vec<String>)> {
+        let env_var = |name: &str| if use_env { env_var(name) } else { None };
+
+        let profile = match profile_name {
+            Some(name) => {
+                let profile = file.profiles.get(name).cloned().with_context(|| {
+                    let available: Vec<&str> = file.profiles.keys().map(String::as_str).collect();
+                    if available.is_empty() {
+                        format!("Profile '{}' not found: no profiles defined in {}", name, display_config_path())
+                    } else {
+                        format!("Profile '{}' not found. Available profiles: {}", name, available.join(", "))
+                    }
+                })?;
+                if profile.provider.is_none() {
+                    anyhow::bail!(
+                        "Profile '{}' does not set a provider. Add e.g. provider = \"gemini\", \"openai\", \"anthropic\", \"azure\" or \"ollama\"",
+                        name
+                    );
+                }
+                profile
+            }
+            None => Profile::default(),
+        };
+        let fallback = profile.fallback.clone();
+
+        let base_url = env_var("WTF_BASE_URL").or(profile.base_url.clone());
+
+        let provider = match env_var("WTF_PROVIDER") {
+            Some(name) => parse_provider(&name)?,
+            // Without a profile, keep the historical behaviour: a custom base URL means
+            // an OpenAI-compatible endpoint, otherwise Gemini.
+            None => profile.provider.unwrap_or(if base_url.is_some() {
+                Provider::OpenAI
+            } else {
+                Provider::Gemini
+            }),
+        };
+
+        let api_key = match env_var("WTF_API_KEY") {
+            Some(key) => Some(key),
+            None => profile_api_key(&profile)?,
+        };
+
+        let api_key = match api_key {
+            Some(key) => key,
+            // A local Ollama server needs no key
+            None if provider == Provider::Ollama => String::new(),
+            // Only Gemini gets the Gemini key: anything else could be a third-party endpoint
+            None if provider != Provider::Gemini => match profile_name {
+                Some(name) => anyhow::bail!(
+                    "No API key for profile '{}'. Set api_key_env, api_key_cmd or api_key in it, or WTF_API_KEY",
+                    name
+                ),
+                None => anyhow::bail!("No API key for the {} provider. Set WTF_API_KEY", provider.name()),
+            },
+            // Gemini's own variable, which fallback profiles may use too
+            None => self::env_var("GEMINI_API_KEY").context(
+                "API key not set.\n\n\
+                Set one of these environment variables:\n\
+                  export WTF_API_KEY='your-key'      # For any provider\n\
+                  export GEMINI_API_KEY='your-key'   # For Gemini (legacy)\n\n\
+                Or configure a profile in ~/.config/wtf/config.toml.\n\n\
+                Get a free Gemini key at: https://aistudio.google.com/app/apikey"
+            )?,
+        };
+
+        let base_url = match base_url.or_else(|| provider.default_base_url().map(str::to_string)) {
+            Some(url) => url,
+            None => anyhow::bail!(
+                "The {:?} provider needs a base URL, e.g. base_url = \"https://my-resource.openai.azure.com\"",
+                provider
+            ),
+        };
+        let model = env_var("WTF_MODEL")
+            .or(profile.model)
+            .unwrap_or_else(|| provider.default_model().to_string());
+
+        let azure = (provider == Provider::Azure).then(|| AzureConfig {
+            deployment: env_var("WTF_AZURE_DEPLOYMENT")
+                .or(profile.deployment)
+                .unwrap_or_else(|| model.clone()),
+            api_version: env_var("WTF_AZURE_API_VERSION")
+                .or(profile.api_version)
+                .unwrap_or_else(|| DEFAULT_AZURE_API_VERSION.to_string()),
+        });
+
+        let ollama = (provider == Provider::Ollama).then_some(OllamaConfig {
+            keep_alive: profile.keep_alive,
+            num_ctx: profile.num_ctx,
+        });
+
+        let config = Config {
+            name: profile_name.unwrap_or(provider.name()).to_string(),
+            api_key,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model,
+            provider,
+            temperature: profile.temperature,
+            max_tokens: profile.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            stream: profile.stream.unwrap_or(true),
+            // Azure only takes json_schema from api-version 2024-08-01-preview on, newer
+            // than the default
+            response_schema: profile.response_schema.unwrap_or(provider != Provider::Azure),
+            retry: RetryPolicy {
+                max_retries: profile.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+                timeout: Duration::from_secs(profile.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            },
+            azure,
+            ollama,
+        };
+
+        Ok((config, fallback))
+    }
+
+    /// Human-readable "profile (provider model)" label for status output.
+    pub fn label(&self) -> String {
+        if self.name == self.provider.name() {
+            format!("{} {}", self.name, self.model)
+        } else {
+            format!("{} ({} {})", self.name, self.provider.name(), self.model)
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn parse_provider(name: &str) -> Result<Provider> {
+    match name.to_lowercase().as_str() {
+        "gemini" => Ok(Provider::Gemini),
+        "openai" => Ok(Provider::OpenAI),
+        "anthropic" => Ok(Provider::Anthropic),
+        "azure" => Ok(Provider::Azure),
+        "ollama" => Ok(Provider::Ollama),
+        _ => anyhow::bail!("Unknown provider '{}'. Supported: gemini, openai, anthropic, azure, ollama", name),
+    }
+}
+
+fn profile_api_key(profile: &Profile) -> Result<Option<String>> {
+    if let Some(key) = &profile.api_key {
+        return Ok(Some(key.clone()));
+    }
+
+    // A profile that names where its key is shouldn't quietly use GEMINI_API_KEY instead
+    if let Some(var) = &profile.api_key_env {
+        let key = env_var(var).with_context(|| format!("api_key_env names {}, which is unset or empty", var))?;
+        return Ok(Some(key));
+    }
+
+    if let Some(cmd) = &profile.api_key_cmd {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .output()
+            .with_context(|| format!("Failed to run api_key_cmd: {}", cmd))?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "api_key_cmd failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if key.is_empty() {
+            anyhow::bail!("api_key_cmd printed no key: {}", cmd);
+        }
+        return Ok(Some(key));
+    }
+
+    Ok(None)
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    env_var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env_var("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("wtf"))
+}
+
+fn config_path() -> Option<PathBuf> {
+    env_var("WTF_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| config_dir().map(|dir| dir.join("config.toml")))
+}
+
+fn display_config_path() -> String {
+    config_path()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|| "~/.config/wtf/config.toml".to_string())
+}
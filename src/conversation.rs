@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Interactive mode's conversation, sent to the model as message history
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Requests, answers, edits and what happened when a command ran, so "now only the
+// big ones" means something. Old turns make way for new ones within a token budget;
+// each leaves a one-line note behind, and the latest notes lead the history.
+
+/// Most notes kept about turns that no longer fit.
+const MAX_NOTES: usize = 10;
+
+/// Rough bytes per token, for the budget. Close enough for English and shell.
+const BYTES_PER_TOKEN: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+struct Turn {
+    role: Role,
+    content: String,
+    /// Stands in for the turn once it has been dropped
+    note: String,
+}
+
+pub struct Conversation {
+    turns: VecDeque<Turn>,
+    /// Notes on dropped turns, oldest first
+    notes: VecDeque<String>,
+    /// In estimated tokens; 0 keeps no history at all
+    budget: usize,
+}
+
+impl Conversation {
+    pub fn new(budget: usize) -> Self {
+        Conversation {
+            turns: VecDeque::new(),
+            notes: VecDeque::new(),
+            budget,
+        }
+    }
+
+    pub fn user(&mut self, content: impl Into<String>, note: impl Into<String>) {
+        self.push(Role::User, content.into(), note.into());
+    }
+
+    pub fn assistant(&mut self, content: impl Into<String>, note: impl Into<String>) {
+        self.push(Role::Assistant, content.into(), note.into());
+    }
+
+    fn push(&mut self, role: Role, content: String, note: String) {
+        if self.budget == 0 {
+            return;
+        }
+        self.turns.push_back(Turn { role, content, note });
+        while self.tokens() > self.budget
+            && let Some(dropped) = self.turns.pop_front()
+        {
+            self.notes.push_back(dropped.note);
+            if self.notes.len() > MAX_NOTES {
+                self.notes.pop_front();
+            }
+        }
+    }
+
+    fn tokens(&self) -> usize {
+        let bytes: usize = self.turns.iter().map(|turn| turn.content.len()).sum::<usize>()
+            + self.notes.iter().map(|note| note.len() + 3).sum::<usize>();
+        bytes.div_ceil(BYTES_PER_TOKEN)
+    }
+
+    /// The messages to send for `prompt`: the history, then the prompt. Turns in a
+    /// row from one side are joined, since not every API takes two user messages in a
+    /// row. The notes on dropped turns come first, so the model hears from the user
+    /// first even when only the answer to a request is left.
+    pub fn messages(&self, prompt: &str) -> Vec<(Role, String)> {
+        let mut messages: Vec<(Role, String)> = Vec::new();
+        if !self.notes.is_empty() {
+            let notes: Vec<String> = self.notes.iter().map(|note| format!("- {}", note)).collect();
+            messages.push((Role::User, format!("Earlier in this session:\n{}", notes.join("\n"))));
+        }
+
+        let turns = self.turns.iter().map(|turn| (turn.role, turn.content.as_str()));
+        for (role, content) in turns.chain([(Role::User, prompt)]) {
+            match messages.last_mut() {
+                Some((last, text)) if *last == role => {
+                    text.push_str("\n\n");
+                    text.push_str(content);
+                }
+                _ => messages.push((role, content.to_string())),
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(messages: &[(Role, String)]) -> Vec<Role> {
+        messages.iter().map(|(role, _)| *role).collect()
+    }
+
+    #[test]
+    fn drops_oldest_turns_first() {
+        // 40 bytes a turn is 10 tokens
+        let mut conversation = Conversation::new(25);
+        conversation.user("a".repeat(40), "first");
+        conversation.assistant("b".repeat(40), "second");
+        conversation.user("c".repeat(40), "third");
+
+        let messages = conversation.messages("now");
+        assert_eq!(messages[0], (Role::User, "Earlier in this session:\n- first".to_string()));
+        assert_eq!(messages[1], (Role::Assistant, "b".repeat(40)));
+        assert_eq!(messages[2], (Role::User, format!("{}\n\nnow", "c".repeat(40))));
+    }
+
+    #[test]
+    fn keeps_the_latest_notes() {
+        let mut conversation = Conversation::new(30);
+        for turn in 0..25 {
+            conversation.user("x".repeat(100), format!("turn {}", turn));
+        }
+
+        let messages = conversation.messages("now");
+        // Nothing else fits, so the prompt joins the notes
+        assert_eq!(messages.len(), 1);
+        let (notes, prompt) = messages[0].1.split_once("\n\n").unwrap();
+        let notes: Vec<&str> = notes.lines().skip(1).collect();
+        assert_eq!(notes.len(), MAX_NOTES);
+        assert_eq!(notes.first(), Some(&"- turn 15"));
+        assert_eq!(notes.last(), Some(&"- turn 24"));
+        assert_eq!(prompt, "now");
+    }
+
+    #[test]
+    fn joins_turns_from_the_same_side() {
+        let mut conversation = Conversation::new(1000);
+        conversation.user("list files", "");
+        conversation.assistant("ls", "");
+        conversation.user("I didn't run it.", "");
+        conversation.user("I changed the command to: ls -a", "");
+
+        let messages = conversation.messages("now hidden ones");
+        assert_eq!(roles(&messages), [Role::User, Role::Assistant, Role::User]);
+        assert_eq!(
+            messages[2].1,
+            "I didn't run it.\n\nI changed the command to: ls -a\n\nnow hidden ones"
+        );
+    }
+
+    #[test]
+    fn starts_with_the_user() {
+        let mut conversation = Conversation::new(20);
+        conversation.user("a".repeat(40), "asked");
+        conversation.assistant("b".repeat(40), "answered");
+        conversation.assistant("c".repeat(40), "added");
+
+        // Only answers are left, so the notes have to come first
+        let messages = conversation.messages("next");
+        assert_eq!(roles(&messages), [Role::User, Role::Assistant, Role::User]);
+        assert!(messages[0].1.starts_with("Earlier in this session:"));
+    }
+
+    #[test]
+    fn no_budget_keeps_nothing() {
+        let mut conversation = Conversation::new(0);
+        conversation.user("list files", "asked");
+        assert_eq!(conversation.messages("next"), [(Role::User, "next".to_string())]);
+    }
+}
@@ -0,0 +1,661 @@
+use anyhow::{Context, Result};
+use portable_pty::{Child, CommandBuilder, MasterPty, PtySize, native_pty_system};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::{fix, shell};
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Running commands from interactive mode on a pseudo-terminal
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// The command sees a real terminal, so progress bars, pagers, editors and password
+// prompts work. Its output is passed through as it arrives, and only the end of it
+// is kept for the fix loop.
+//
+// All commands of a session run in one long-lived shell, so `cd` and `export`
+// carry over to the next request. The shell reads a line from a FIFO for each
+// command, sources the file holding it, writes its environment to a file, then
+// reports the exit status and its working directory on the terminal in an escape
+// sequence that is not shown.
+
+/// How much raw output is kept, from the end, before escape sequences are stripped.
+const TAIL_BYTES: usize = 16_000;
+
+/// How long to wait for output after a command run on its own exits. Background jobs
+/// it started keep the terminal open, and wtf shouldn't wait for them.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Start of the sequence the session shell prints after each command.
+const MARKER: &[u8] = b"\x1b]777;wtf-done;";
+
+/// Variables the shell sets itself, which say nothing about what the user did.
+const SHELL_VARIABLES: &[&str] = &["PWD", "OLDPWD", "SHLVL", "_"];
+
+/// Run by the session shell, with `{fifo}`, `{file}` and `{env}` replaced by the
+/// quoted paths of the FIFO, the command file and the environment dump. They are
+/// written in rather than passed as `$1` and `$2`, so commands see no positional
+/// parameters or variables of wtf's; the status goes through a subshell for the
+/// same reason. Ctrl+C is trapped, not ignored, so it still stops the command but
+/// not the shell.
+const SESSION_SCRIPT: &str = r#"trap : INT
+trap '' TSTP
+exec 3<{fifo}
+while read -r _ <&3; do
+  { . {file}; } 3<&-
+  printf '\033]777;wtf-done;%s;%s\007' "$(s=$?; command -p env >{env}; echo "$s")" "$PWD" >/dev/tty
+done"#;
+
+/// How a command ended.
+pub struct Execution {
+    pub exit_code: u32,
+    /// e.g. "SIGINT", when a signal ended it
+    pub signal: Option<String>,
+    /// The end of what it printed, as plain text
+    pub output: String,
+}
+
+impl Execution {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0 && self.signal.is_none()
+    }
+
+    /// Ctrl+C or Ctrl+\, rather than a crash or something else killing it.
+    pub fn stopped_by_user(&self) -> bool {
+        matches!(self.signal.as_deref(), Some("SIGINT" | "SIGQUIT"))
+    }
+}
+
+/// The shell interactive mode runs commands in, where it is and what it exported.
+pub struct Session {
+    cwd: PathBuf,
+    /// wtf's own environment, which the shell starts out with
+    inherited: HashMap<String, String>,
+    /// Names of the variables the shell has set, changed or unset since
+    variables: Vec<String>,
+    /// Started with the first command, and again after one exits the shell
+    shell: Option<Shell>,
+    /// Cleared when the shell can't be started; each command then runs on its own
+    persistent: bool,
+}
+
+impl Session {
+    pub fn new(cwd: PathBuf) -> Self {
+        Session {
+            cwd,
+            inherited: env::vars_os()
+                .map(|(name, value)| (name.to_string_lossy().into_owned(), value.to_string_lossy().into_owned()))
+                .collect(),
+            variables: Vec::new(),
+            shell: None,
+            persistent: cfg!(unix),
+        }
+    }
+
+    /// The shell's working directory as of the last command.
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    /// Environment variables changed by earlier commands, by name. Values are left out,
+    /// since they can be secrets; unset ones are marked "(unset)".
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    pub fn run(&mut self, command: &str) -> Result<Execution> {
+        if self.shell.is_none() && self.persistent {
+            match Shell::start(&self.cwd) {
+                Ok(shell) => self.shell = Some(shell),
+                Err(e) => {
+                    eprintln!("\x1b[33m⚠️  Could not start a shell for the session, so cd and export won't carry over: {:#}\x1b[0m", e);
+                    self.persistent = false;
+                }
+            }
+        }
+
+        let Some(shell) = &mut self.shell else {
+            return run_once(command, &self.cwd);
+        };
+        let (execution, cwd) = shell.run(command)?;
+        match cwd {
+            Some(cwd) => {
+                self.cwd = cwd;
+                if let Some(environment) = shell.environment() {
+                    self.variables = changed_variables(&self.inherited, &environment);
+                }
+            }
+            // The command ended the shell (`exit`); the next one starts a new shell here,
+            // with wtf's environment
+            None => {
+                self.shell = None;
+                self.variables.clear();
+            }
+        }
+        Ok(execution)
+    }
+}
+
+/// What the session shell's terminal reports.
+enum Event {
+    Done { status: u32, cwd: PathBuf },
+    Exited,
+}
+
+struct Shell {
+    child: Box<dyn Child + Send + Sync>,
+    /// Keeps the terminal open
+    _master: Box<dyn MasterPty + Send>,
+    input: Box<dyn Write + Send>,
+    /// The shell reads a line from this for each command
+    commands: File,
+    /// Holds the FIFO and the file with the command to run
+    dir: PathBuf,
+    events: Receiver<Event>,
+    tail: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Shell {
+    fn start(cwd: &Path) -> Result<Self> {
+        let dir = env::temp_dir().join(format!("wtf-session-{}", process::id()));
+        make_fifo(&dir)?;
+        let fifo = dir.join("commands");
+
+        let pair = native_pty_system().openpty(terminal_size()).context("Failed to open a terminal")?;
+        let mut builder = CommandBuilder::new(shell::target_shell());
+        let script = SESSION_SCRIPT
+            .replace("{fifo}", &single_quote(&fifo))
+            .replace("{file}", &single_quote(&dir.join("command")))
+            .replace("{env}", &single_quote(&dir.join("env")));
+        builder.args(["-c", &script]);
+        builder.cwd(cwd);
+        let child = pair.slave.spawn_command(builder).context("Failed to start the shell")?;
+        drop(pair.slave);
+
+        // Read and write, so opening doesn't wait for the shell to open the other end
+        let commands = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&fifo)
+            .context("Failed to open the shell's command queue")?;
+        let reader = pair.master.try_clone_reader().context("Failed to read the shell's output")?;
+        let input = pair.master.take_writer().context("Failed to forward input to the shell")?;
+
+        let tail = Arc::new(Mutex::new(Vec::new()));
+        let (events_tx, events) = mpsc::channel();
+        let shared = Arc::clone(&tail);
+        thread::spawn(move || pass_through(reader, &shared, &events_tx));
+
+        Ok(Shell {
+            child,
+            _master: pair.master,
+            input,
+            commands,
+            dir,
+            events,
+            tail,
+        })
+    }
+
+    /// Run `command`, returning how it ended and the shell's new working directory,
+    /// or `None` for the directory if the shell exited.
+    fn run(&mut self, command: &str) -> Result<(Execution, Option<PathBuf>)> {
+        // A report left over from a command that didn't wait for its background jobs
+        while self.events.try_recv().is_ok() {}
+        fs::write(self.dir.join("command"), command).context("Failed to pass the command to the shell")?;
+        self.tail.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        self.commands
+            .write_all(b"\n")
+            .context("Failed to pass the command to the shell")?;
+
+        let events = &self.events;
+        let event = with_input(&mut *self.input, || events.recv());
+
+        let tail = std::mem::take(&mut *self.tail.lock().unwrap_or_else(|e| e.into_inner()));
+        end_line(&tail);
+        let output = plain_tail(&tail);
+        match event {
+            Ok(Event::Done { status, cwd }) => Ok((
+                Execution {
+                    exit_code: status,
+                    signal: signal_name(status),
+                    output,
+                },
+                Some(cwd),
+            )),
+            Ok(Event::Exited) | Err(_) => {
+                let status = self.child.wait().context("Failed to wait for the shell")?;
+                Ok((
+                    Execution {
+                        exit_code: status.exit_code(),
+                        signal: status.signal().map(str::to_string),
+                        output,
+                    },
+                    None,
+                ))
+            }
+        }
+    }
+}
+
+impl Shell {
+    /// The environment as of the last command, if the shell managed to write it.
+    fn environment(&self) -> Option<HashMap<String, String>> {
+        let text = fs::read_to_string(self.dir.join("env")).ok()?;
+        let mut environment = HashMap::new();
+        let mut last: Option<String> = None;
+        for line in text.lines() {
+            match line.split_once('=') {
+                Some((name, value)) if is_name(name) => {
+                    environment.insert(name.to_string(), value.to_string());
+                    last = Some(name.to_string());
+                }
+                // The rest of a value with newlines in it
+                _ => {
+                    if let Some(value) = last.as_ref().and_then(|name| environment.get_mut(name)) {
+                        value.push('\n');
+                        value.push_str(line);
+                    }
+                }
+            }
+        }
+        Some(environment)
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Copy the shell's output to ours and into `tail`, leaving out the reports after
+/// each command, which go to `events` instead.
+fn pass_through(mut reader: Box<dyn Read + Send>, tail: &Mutex<Vec<u8>>, events: &Sender<Event>) {
+    let mut stdout = io::stdout();
+    let mut show = |bytes: &[u8]| {
+        let _ = stdout.write_all(bytes);
+        let _ = stdout.flush();
+        let mut tail = tail.lock().unwrap_or_else(|e| e.into_inner());
+        tail.extend_from_slice(bytes);
+        if tail.len() > 2 * TAIL_BYTES {
+            let excess = tail.len() - TAIL_BYTES;
+            tail.drain(..excess);
+        }
+    };
+    let mut report = |status, cwd| {
+        let _ = events.send(Event::Done { status, cwd });
+    };
+
+    let mut reports = Reports::default();
+    let mut buf = [0; 4096];
+    // Ends with an error rather than EOF on Linux once the terminal closes
+    while let Ok(n) = reader.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        reports.feed(&buf[..n], &mut show, &mut report);
+    }
+    show(&reports.pending);
+    let _ = events.send(Event::Exited);
+}
+
+/// Picks the reports the session shell prints after each command out of its output.
+/// A read can end halfway through one, so what could be the start of a report is
+/// held back until the next read.
+#[derive(Default)]
+struct Reports {
+    pending: Vec<u8>,
+}
+
+impl Reports {
+    /// Pass on `bytes`: output to `show`, and the status and directory of each report
+    /// to `report`, in the order they came.
+    fn feed(&mut self, bytes: &[u8], show: &mut impl FnMut(&[u8]), report: &mut impl FnMut(u32, PathBuf)) {
+        let pending = &mut self.pending;
+        pending.extend_from_slice(bytes);
+        loop {
+            let Some(start) = pending.windows(MARKER.len()).position(|window| window == MARKER) else {
+                let keep = (1..MARKER.len())
+                    .rev()
+                    .find(|&len| pending.ends_with(&MARKER[..len]))
+                    .unwrap_or(0);
+                let shown = pending.len() - keep;
+                show(&pending[..shown]);
+                pending.drain(..shown);
+                return;
+            };
+            show(&pending[..start]);
+            pending.drain(..start);
+            let Some(end) = pending.iter().position(|&b| b == b'\x07') else {
+                return;
+            };
+            let text = String::from_utf8_lossy(&pending[MARKER.len()..end]).into_owned();
+            pending.drain(..=end);
+            if let Some((status, cwd)) = text.split_once(';') {
+                report(status.parse().unwrap_or(1), PathBuf::from(cwd));
+            }
+        }
+    }
+}
+
+/// Run `command` with its own shell on a new terminal, for when there is no session
+/// shell.
+fn run_once(command: &str, cwd: &Path) -> Result<Execution> {
+    let pair = native_pty_system().openpty(terminal_size()).context("Failed to open a terminal for the command")?;
+
+    let mut builder = if cfg!(target_os = "windows") {
+        let mut builder = CommandBuilder::new("cmd");
+        builder.arg("/C");
+        builder
+    } else {
+        let mut builder = CommandBuilder::new("sh");
+        builder.arg("-c");
+        builder
+    };
+    builder.arg(command);
+    // Without this the command starts in the home directory
+    builder.cwd(cwd);
+
+    let mut child = pair.slave.spawn_command(builder).context("Failed to execute command")?;
+    // Only the child holds the terminal open now, so the output ends when it does
+    drop(pair.slave);
+
+    let mut reader = pair.master.try_clone_reader().context("Failed to read the command's output")?;
+    let mut writer = pair.master.take_writer().context("Failed to forward input to the command")?;
+
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut tail = Vec::new();
+        let mut buf = [0; 4096];
+        let mut stdout = io::stdout();
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            let _ = stdout.write_all(&buf[..n]);
+            let _ = stdout.flush();
+            tail.extend_from_slice(&buf[..n]);
+            if tail.len() > 2 * TAIL_BYTES {
+                tail.drain(..tail.len() - TAIL_BYTES);
+            }
+        }
+        let _ = done_tx.send(tail);
+    });
+
+    let status = with_input(&mut *writer, || child.wait()).context("Failed to wait for the command")?;
+
+    let tail = done_rx.recv_timeout(DRAIN_TIMEOUT).unwrap_or_default();
+    end_line(&tail);
+    Ok(Execution {
+        exit_code: status.exit_code(),
+        signal: status.signal().map(str::to_string),
+        output: plain_tail(&tail),
+    })
+}
+
+/// Forward keystrokes to `input` while `wait` runs.
+fn with_input<T>(input: &mut (dyn Write + Send), wait: impl FnOnce() -> T) -> T {
+    let raw_mode = RawMode::enable();
+    let finished = AtomicBool::new(false);
+    thread::scope(|scope| {
+        if raw_mode.is_some() {
+            scope.spawn(|| forward_input(input, &finished));
+        }
+        let result = wait();
+        finished.store(true, Ordering::Relaxed);
+        result
+    })
+}
+
+/// The size of our terminal, for the command's.
+fn terminal_size() -> PtySize {
+    crossterm::terminal::size()
+        .map(|(cols, rows)| PtySize {
+            rows,
+            cols,
+            ..PtySize::default()
+        })
+        .unwrap_or_default()
+}
+
+/// What wtf prints next starts on a line of its own.
+fn end_line(tail: &[u8]) {
+    if tail.last().is_some_and(|&last| last != b'\n') {
+        println!();
+    }
+}
+
+fn plain_tail(tail: &[u8]) -> String {
+    fix::tail(strip_escapes(&String::from_utf8_lossy(tail)).as_bytes())
+}
+
+/// Puts our terminal in raw mode so every keystroke, Ctrl+C included, goes to the
+/// command's terminal as is. Restored when dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        (io::stdin().is_terminal() && crossterm::terminal::enable_raw_mode().is_ok()).then_some(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+}
+
+/// A private directory holding the FIFO the session shell reads commands from.
+#[cfg(unix)]
+fn make_fifo(dir: &Path) -> Result<()> {
+    use nix::sys::stat::Mode;
+    use std::os::unix::fs::DirBuilderExt;
+
+    // Left behind by a shell that was started before
+    let _ = fs::remove_dir_all(dir);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    nix::unistd::mkfifo(&dir.join("commands"), Mode::S_IRUSR | Mode::S_IWUSR).context("Failed to create a FIFO")?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_fifo(_dir: &Path) -> Result<()> {
+    anyhow::bail!("not supported on this platform")
+}
+
+/// Names of the variables in `current` that aren't as in `inherited`, sorted.
+fn changed_variables(inherited: &HashMap<String, String>, current: &HashMap<String, String>) -> Vec<String> {
+    let mut changed: Vec<String> = current
+        .iter()
+        .filter(|(name, value)| inherited.get(*name) != Some(value))
+        .map(|(name, _)| name.clone())
+        .chain(
+            inherited
+                .keys()
+                .filter(|name| !current.contains_key(*name))
+                .map(|name| format!("{} (unset)", name)),
+        )
+        .filter(|name| !SHELL_VARIABLES.contains(&name.split(' ').next().unwrap_or_default()))
+        .collect();
+    changed.sort();
+    changed
+}
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn single_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// Shells report a command killed by signal N as exit status 128 + N. A command can
+/// exit with such a status too (`exit 137`), which can't be told apart.
+#[cfg(unix)]
+fn signal_name(status: u32) -> Option<String> {
+    let signal = status.checked_sub(128).filter(|&n| n > 0)?;
+    nix::sys::signal::Signal::try_from(signal as i32)
+        .ok()
+        .map(|signal| signal.as_str().to_string())
+}
+
+#[cfg(not(unix))]
+fn signal_name(_status: u32) -> Option<String> {
+    None
+}
+
+/// Copy keystrokes to the command until it exits. Reads only when input is waiting,
+/// so nothing typed after the command is done gets swallowed.
+#[cfg(unix)]
+fn forward_input(writer: &mut (dyn Write + Send), finished: &AtomicBool) {
+    use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+    use std::os::fd::AsFd;
+
+    let stdin = io::stdin();
+    let mut buf = [0; 1024];
+    while !finished.load(Ordering::Relaxed) {
+        let mut fds = [PollFd::new(stdin.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, PollTimeout::from(50u8)) {
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+        // Straight from the file descriptor: io::stdin() would buffer what the prompt reads next
+        match nix::unistd::read(stdin.as_fd(), &mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if writer.write_all(&buf[..n]).and_then(|_| writer.flush()).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Input isn't forwarded on Windows; the command gets an empty stdin.
+#[cfg(not(unix))]
+fn forward_input(_writer: &mut (dyn Write + Send), _finished: &AtomicBool) {}
+
+/// Terminal output as plain text: no colors or cursor movement, and of each line
+/// only what was left after the last carriage return (progress bars).
+fn strip_escapes(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameters up to a final byte in @..~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: up to BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    plain
+        .split('\n')
+        .map(|line| {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            line.rsplit('\r').next().unwrap_or(line)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `reads` one after another, returning the output shown and the reports.
+    fn feed(reads: &[&[u8]]) -> (String, Vec<(u32, PathBuf)>) {
+        let mut reports = Reports::default();
+        let mut shown = Vec::new();
+        let mut done = Vec::new();
+        for read in reads {
+            reports.feed(read, &mut |bytes: &[u8]| shown.extend_from_slice(bytes), &mut |status, cwd| {
+                done.push((status, cwd))
+            });
+        }
+        (String::from_utf8(shown).unwrap(), done)
+    }
+
+    #[test]
+    fn reports_are_taken_out_of_the_output() {
+        let (shown, done) = feed(&[b"hello\r\n\x1b]777;wtf-done;0;/tmp\x07"]);
+        assert_eq!(shown, "hello\r\n");
+        assert_eq!(done, [(0, PathBuf::from("/tmp"))]);
+    }
+
+    #[test]
+    fn reports_cut_by_a_read_are_put_together() {
+        let (shown, done) = feed(&[b"out\x1b]77", b"7;wtf-do", b"ne;2;/home/me", b"\x07next"]);
+        assert_eq!(shown, "outnext");
+        assert_eq!(done, [(2, PathBuf::from("/home/me"))]);
+    }
+
+    #[test]
+    fn other_escapes_pass_through() {
+        let (shown, done) = feed(&[b"\x1b]0;title\x07\x1b[31mred\x1b[0m"]);
+        assert_eq!(shown, "\x1b]0;title\x07\x1b[31mred\x1b[0m");
+        assert!(done.is_empty());
+    }
+
+    #[test]
+    fn directories_keep_their_semicolons() {
+        let (_, done) = feed(&[b"\x1b]777;wtf-done;130;/tmp/a;b\x07"]);
+        assert_eq!(done, [(130, PathBuf::from("/tmp/a;b"))]);
+    }
+
+    #[test]
+    fn escapes_are_stripped() {
+        assert_eq!(strip_escapes("\x1b[1;32mok\x1b[0m done"), "ok done");
+        assert_eq!(strip_escapes("\x1b]0;title\x07text\x1b]8;;url\x1b\\link"), "textlink");
+    }
+
+    #[test]
+    fn progress_bars_keep_their_last_state() {
+        assert_eq!(strip_escapes(" 10%\r 50%\r100%\r\ndone\r\n"), "100%\ndone\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn statuses_above_128_name_the_signal() {
+        assert_eq!(signal_name(130).as_deref(), Some("SIGINT"));
+        assert_eq!(signal_name(137).as_deref(), Some("SIGKILL"));
+        assert_eq!(signal_name(143).as_deref(), Some("SIGTERM"));
+        assert_eq!(signal_name(1), None);
+        assert_eq!(signal_name(128), None);
+    }
+}
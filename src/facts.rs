@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use crate::shell;
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Facts about the machine, for the system prompt
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Without these the model guesses, and Linux users get `sed -i ''` and `pbcopy`.
+
+/// Detection runs a few programs, so the result is reused for a day.
+const CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const PACKAGE_MANAGERS: &[&str] = &[
+    "brew", "port", "apt", "dnf", "yum", "pacman", "zypper", "apk", "emerge", "xbps-install", "nix", "pkg",
+];
+
+/// Tools worth telling the model about because they change what the best command is.
+const NOTABLE_TOOLS: &[&str] = &[
+    "git", "docker", "podman", "kubectl", "systemctl", "launchctl", "rg", "fd", "fzf", "jq", "yq", "bat",
+    "eza", "gsed", "gawk", "python3", "node", "curl", "wget", "pbcopy", "xclip", "wl-copy", "tmux",
+];
+
+#[derive(Serialize, Deserialize)]
+pub struct SystemFacts {
+    /// e.g. "Ubuntu 24.04 LTS (linux x86_64)"
+    pub os: String,
+    /// Name of the user's login shell; read on every run rather than cached
+    #[serde(skip)]
+    pub shell: String,
+    /// "GNU", "BSD" or "BusyBox"
+    pub coreutils: String,
+    pub sed: String,
+    pub package_managers: Vec<String>,
+    pub tools: Vec<String>,
+}
+
+impl SystemFacts {
+    /// The cached facts if they are recent enough, otherwise detect and cache them.
+    pub fn load() -> Self {
+        let path = cache_path();
+        let mut facts = path
+            .as_ref()
+            .filter(|path| {
+                fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .is_some_and(|age| age < CACHE_TTL)
+            })
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| serde_json::from_str::<SystemFacts>(&content).ok())
+            .unwrap_or_else(|| {
+                let facts = Self::detect();
+                // Caching is only an optimization
+                if let Some(path) = &path
+                    && let Some(dir) = path.parent()
+                    && fs::create_dir_all(dir).is_ok()
+                    && let Ok(json) = serde_json::to_string(&facts)
+                {
+                    let _ = fs::write(path, json);
+                }
+                facts
+            });
+
+        facts.shell = env::var("SHELL")
+            .ok()
+            .and_then(|path| path.rsplit('/').next().map(str::to_string))
+            .unwrap_or_else(|| "sh".to_string());
+        facts
+    }
+
+    fn detect() -> Self {
+        SystemFacts {
+            os: detect_os(),
+            shell: String::new(),
+            coreutils: flavor("ls"),
+            sed: flavor("sed"),
+            package_managers: installed(PACKAGE_MANAGERS),
+            tools: installed(NOTABLE_TOOLS),
+        }
+    }
+
+    /// Render the section appended to the system prompt.
+    pub fn prompt_section(&self) -> String {
+        let mut section = String::from("\nThe user's system:\n");
+        section.push_str(&format!("- OS: {}\n", self.os));
+        section.push_str(&format!("- Shell: {}\n", self.shell));
+        section.push_str(&format!("- Userland: {} coreutils, {} sed\n", self.coreutils, self.sed));
+        if !self.package_managers.is_empty() {
+            section.push_str(&format!("- Package manager: {}\n", self.package_managers.join(", ")));
+        }
+        if !self.tools.is_empty() {
+            section.push_str(&format!("- Installed: {}\n", self.tools.join(", ")));
+        }
+        section.push_str("Write commands that work on this system, with the flags its versions of the tools accept.\n");
+        section
+    }
+}
+
+fn cache_path() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("wtf").join("system.json"))
+}
+
+fn detect_os() -> String {
+    let platform = format!("{} {}", env::consts::OS, env::consts::ARCH);
+
+    let name = match env::consts::OS {
+        "macos" => run("sw_vers", &["-productVersion"]).map(|version| format!("macOS {}", version.trim())),
+        _ => fs::read_to_string("/etc/os-release").ok().and_then(|release| {
+            release.lines().find_map(|line| {
+                line.strip_prefix("PRETTY_NAME=")
+                    .map(|name| name.trim_matches('"').to_string())
+            })
+        }),
+    };
+
+    match name {
+        Some(name) => format!("{} ({})", name, platform),
+        None => platform,
+    }
+}
+
+/// Whether `program` is the GNU, BusyBox or BSD version, going by `--version`
+/// (which BSD tools reject).
+fn flavor(program: &str) -> String {
+    let output = Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output();
+    let text = match output {
+        Ok(output) => format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(_) => return "unknown".to_string(),
+    };
+
+    if text.contains("GNU") {
+        "GNU"
+    } else if text.contains("BusyBox") {
+        "BusyBox"
+    } else {
+        "BSD"
+    }
+    .to_string()
+}
+
+fn installed(programs: &[&str]) -> Vec<String> {
+    programs
+        .iter()
+        .filter(|program| shell::find_program(program).is_some())
+        .map(|program| program.to_string())
+        .collect()
+}
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
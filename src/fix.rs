@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::shell;
+
+// ─────────────────────────────────────────────────────────────────────────────
+// `wtf fix`: the last command the shell ran, as recorded by the init script hooks
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// After every command the hooks write a state file named after the shell's PID:
+// the exit status, the working directory, then the command itself.
+
+/// A re-run that takes longer than this is stopped.
+const RERUN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the rest of the output once the command is done. Anything it
+/// left running in the background can hold stderr open for much longer.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How much of the re-run's error output is kept, from the end.
+const OUTPUT_LIMIT: usize = 4000;
+
+/// Sessions whose state file hasn't been written to for this long are gone.
+const STALE_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+pub struct LastCommand {
+    pub command: String,
+    pub exit_status: i32,
+    pub cwd: PathBuf,
+    /// What a re-run printed on stderr, if the user allowed one
+    pub output: Option<String>,
+}
+
+impl LastCommand {
+    /// The last command run in this shell session.
+    pub fn load() -> Result<Self> {
+        let session = env::var("WTF_SESSION").ok().filter(|s| !s.is_empty()).context(
+            "No command history from the shell. Enable the integration first: eval \"$(command wtf --init zsh)\"",
+        )?;
+        let dir = sessions_dir().context("Could not find HOME directory")?;
+        prune_stale(&dir);
+
+        let path = dir.join(&session);
+        let state = fs::read_to_string(&path).context("No command has run in this shell yet")?;
+        let mut lines = state.splitn(3, '\n');
+        let (Some(status), Some(cwd), Some(command)) = (lines.next(), lines.next(), lines.next()) else {
+            anyhow::bail!("{} is not a state file written by the shell integration", path.display());
+        };
+
+        Ok(LastCommand {
+            command: command.trim_end().to_string(),
+            exit_status: status
+                .trim()
+                .parse()
+                .with_context(|| format!("{} is not a state file written by the shell integration", path.display()))?,
+            cwd: PathBuf::from(cwd),
+            output: None,
+        })
+    }
+
+    /// Run the command again in its directory to see its error output.
+    pub fn rerun(&mut self) -> Result<()> {
+        let mut command = Command::new(shell::target_shell());
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .current_dir(&self.cwd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        // A process group of its own, so a timeout stops the whole pipeline, not just the shell
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn().context("Failed to re-run the command")?;
+
+        let mut stderr = child.stderr.take().context("Failed to capture stderr")?;
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let (done, finished) = mpsc::channel();
+        thread::spawn({
+            let captured = Arc::clone(&captured);
+            move || {
+                let mut buffer = [0; 4096];
+                while let Ok(n) = stderr.read(&mut buffer)
+                    && n > 0
+                {
+                    captured.lock().unwrap().extend_from_slice(&buffer[..n]);
+                }
+                let _ = done.send(());
+            }
+        });
+
+        let deadline = Instant::now() + RERUN_TIMEOUT;
+        let mut timed_out = false;
+        while child.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                kill_group(&mut child);
+                let _ = child.wait();
+                timed_out = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let _ = finished.recv_timeout(deadline.saturating_duration_since(Instant::now()).max(DRAIN_TIMEOUT));
+        let mut text = tail(&captured.lock().unwrap());
+        if timed_out {
+            text.push_str(&format!("\n(stopped after {} seconds)", RERUN_TIMEOUT.as_secs()));
+        }
+        self.output = Some(text);
+        Ok(())
+    }
+
+    /// The request sent to the model.
+    pub fn prompt(&self) -> String {
+        let mut prompt = format!(
+            "This command failed with exit status {} in {}:\n{}\n",
+            self.exit_status,
+            self.cwd.display(),
+            self.command
+        );
+        match self.output.as_deref() {
+            Some("") => prompt.push_str("\nIt printed nothing.\n"),
+            Some(output) => prompt.push_str(&format!("\nWhat it printed:\n{}\n", output)),
+            None => {}
+        }
+        prompt.push_str("\nGive a corrected command that does what it was meant to do.");
+        prompt
+    }
+}
+
+/// Stop the re-run and everything it started.
+fn kill_group(child: &mut std::process::Child) {
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{killpg, Signal};
+        use nix::unistd::Pid;
+        let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+    }
+    let _ = child.kill();
+}
+
+/// The last `OUTPUT_LIMIT` bytes of a command's output, which is where the error usually is.
+pub fn tail(output: &[u8]) -> String {
+    let start = output.len().saturating_sub(OUTPUT_LIMIT);
+    String::from_utf8_lossy(&output[start..]).trim().to_string()
+}
+
+/// `$XDG_STATE_HOME/wtf/sessions`, where the init scripts write. Keep in sync with them.
+fn sessions_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state")))
+        .map(|dir| dir.join("wtf").join("sessions"))
+}
+
+/// Remove the state of shells that have long since exited.
+fn prune_stale(dir: &std::path::Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let stale = entry
+            .metadata()
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age > STALE_AFTER);
+        if stale {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use serde::Serialize;
+
+use crate::shell::{self, basename, Command, Pipeline, SimpleCommand, Word, WordPart};
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Lint rules for generated commands
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Mistakes that pass the syntax check but bite on real data. The IDs are the ones
+// shellcheck uses for the same problems, so they can be looked up on its wiki.
+
+pub struct Rule {
+    pub id: &'static str,
+    /// How to fix it, shown with `--explain`
+    pub help: &'static str,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        id: "SC2086",
+        help: "Double quote expansions (\"$var\") so they stay one argument.",
+    },
+    Rule {
+        id: "SC2012",
+        help: "Use a glob (for f in *) or find instead of parsing ls.",
+    },
+    Rule {
+        id: "SC2038",
+        help: "Use find -print0 | xargs -0, or find -exec … {} +.",
+    },
+    Rule {
+        id: "SC2002",
+        help: "Pass the file directly (cmd file) or redirect it (cmd < file).",
+    },
+    Rule {
+        id: "SC2115",
+        help: "Write \"${var:?}\" so the command fails instead of running on an empty path.",
+    },
+];
+
+#[derive(Serialize, Clone, PartialEq)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn help(&self) -> &'static str {
+        RULES.iter().find(|rule| rule.id == self.rule).map_or("", |rule| rule.help)
+    }
+}
+
+/// Builtins whose `NAME=$value` arguments are assignments, so they are not split.
+const DECLARATIONS: &[&str] = &["export", "local", "declare", "readonly", "typeset"];
+
+/// Where `ls` output is only looked at, not parsed.
+const PAGERS: &[&str] = &["less", "more", "most", "bat"];
+
+/// Run every rule that `enabled` allows over `command`. Commands that don't parse
+/// get no findings; the syntax check reports those.
+pub fn lint(command: &str, enabled: impl Fn(&str) -> bool) -> Vec<Finding> {
+    let Ok(script) = shell::parse(command) else {
+        return Vec::new();
+    };
+
+    let mut findings = Vec::new();
+    let mut report = |rule: &'static str, message: String| {
+        let finding = Finding { rule, message };
+        if enabled(rule) && !findings.contains(&finding) {
+            findings.push(finding);
+        }
+    };
+
+    // zsh neither splits nor globs unquoted expansions
+    let splits_words = shell::target_shell() != "zsh";
+
+    for command in script.simple_commands() {
+        let program = command.program();
+        let program = program.as_deref().map(basename);
+
+        let skip_quoting = program.is_some_and(|p| p == "[[" || DECLARATIONS.contains(&p));
+        if splits_words && !skip_quoting {
+            for word in command.words.iter().chain(command.redirects.iter().map(|r| &r.target)) {
+                for param in unquoted_params(word) {
+                    report(
+                        "SC2086",
+                        format!("{} is unquoted, so it is split on spaces and glob-expanded", param),
+                    );
+                }
+            }
+        }
+
+        if program == Some("rm") {
+            check_rm(command, &mut report);
+        }
+    }
+
+    for pipeline in script.pipelines() {
+        check_pipeline(pipeline, &mut report);
+
+        // for f in $(ls …)
+        for command in &pipeline.commands {
+            if let Command::Compound { words, .. } = command
+                && words.iter().any(runs_ls)
+            {
+                report("SC2012", "Loops over the output of ls, which breaks on unusual file names".to_string());
+            }
+        }
+    }
+
+    findings
+}
+
+fn check_pipeline(pipeline: &Pipeline, report: &mut impl FnMut(&'static str, String)) {
+    let simple: Vec<Option<&SimpleCommand>> = pipeline
+        .commands
+        .iter()
+        .map(|command| match command {
+            Command::Simple(simple) => Some(simple),
+            _ => None,
+        })
+        .collect();
+
+    for (index, pair) in simple.windows(2).enumerate() {
+        let (Some(left), right) = (pair[0], pair[1]) else {
+            continue;
+        };
+        let left_program = left.program();
+        let right_program = right.and_then(SimpleCommand::program);
+        let right_program = right_program.as_deref().map(basename);
+        let left_args = left.argv();
+
+        match left_program.as_deref().map(basename) {
+            Some("ls") if !right_program.is_some_and(|p| PAGERS.contains(&p)) => {
+                report("SC2012", "Parses the output of ls, which breaks on unusual file names".to_string());
+            }
+            Some("find") if right_program == Some("xargs") => {
+                let right_args = right.map(SimpleCommand::argv).unwrap_or_default();
+                let null_separated = left_args.iter().any(|a| a == "-print0")
+                    && right_args.iter().any(|a| matches!(a.as_str(), "-0" | "--null"));
+                if !null_separated {
+                    report(
+                        "SC2038",
+                        "find | xargs splits file names on spaces and newlines".to_string(),
+                    );
+                }
+            }
+            // Only the first command of a pipeline reads a file on behalf of the rest
+            Some("cat") if index == 0 && left_args.len() == 2 && !left_args[1].starts_with('-') => {
+                report("SC2002", format!("Useless cat: {} can be read by the next command directly", left_args[1]));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `rm -r "$dir/"` with `dir` unset deletes `/`.
+fn check_rm(command: &SimpleCommand, report: &mut impl FnMut(&'static str, String)) {
+    let argv = command.argv();
+    let recursive = argv
+        .iter()
+        .any(|a| a == "--recursive" || (a.starts_with('-') && !a.starts_with("--") && a.contains(['r', 'R'])));
+
+    for word in command.words.iter().skip(1) {
+        let Some(WordPart::Param { text, .. }) = word.parts.first() else {
+            continue;
+        };
+        if text.contains(":?") || is_special(text) {
+            continue;
+        }
+        // What is left of the path when the variable is empty
+        let value = word.value();
+        let rest = &value[text.len()..];
+        let consequence = if rest.starts_with('/') {
+            "this deletes from /".to_string()
+        } else if !rest.is_empty() {
+            format!("this deletes {} in the current directory", rest)
+        } else if recursive {
+            "rm only gets the other arguments, if any".to_string()
+        } else {
+            continue;
+        };
+        report("SC2115", format!("rm on {}: if {} is empty {}", value, text, consequence));
+    }
+}
+
+/// Parameter expansions in `word` outside double quotes, except the ones that
+/// always expand to a single number.
+fn unquoted_params(word: &Word) -> Vec<&str> {
+    word.parts
+        .iter()
+        .filter_map(|part| match part {
+            WordPart::Param { text, quoted: false } if !is_special(text) && !text.starts_with("${#") => {
+                Some(text.as_str())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// `$?`, `$$`, `$#` and `$!`
+fn is_special(param: &str) -> bool {
+    matches!(param, "$?" | "$$" | "$#" | "$!")
+}
+
+fn runs_ls(word: &Word) -> bool {
+    word.parts.iter().any(|part| match part {
+        WordPart::Command { script, .. } => script
+            .simple_commands()
+            .first()
+            .and_then(|command| command.program())
+            .is_some_and(|program| basename(&program) == "ls"),
+        _ => false,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rm_messages(command: &str) -> Vec<String> {
+        lint(command, |rule| rule == "SC2115")
+            .into_iter()
+            .map(|finding| finding.message)
+            .collect()
+    }
+
+    #[test]
+    fn rm_says_what_an_empty_variable_deletes() {
+        assert_eq!(
+            rm_messages(r#"rm -rf "$dir/"*"#),
+            ["rm on $dir/*: if $dir is empty this deletes from /"]
+        );
+        assert_eq!(
+            rm_messages(r#"rm "$prefix"*.log"#),
+            ["rm on $prefix*.log: if $prefix is empty this deletes *.log in the current directory"]
+        );
+        assert_eq!(
+            rm_messages(r#"rm -r "$dir""#),
+            ["rm on $dir: if $dir is empty rm only gets the other arguments, if any"]
+        );
+    }
+
+    #[test]
+    fn rm_with_a_guarded_or_plain_variable_is_fine() {
+        assert!(rm_messages(r#"rm -rf "${dir:?}/""#).is_empty());
+        assert!(rm_messages(r#"rm "$file""#).is_empty());
+    }
+}
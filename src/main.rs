@@ -1,19 +1,49 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::fmt;
 use std::fs::{self, OpenOptions};
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use chrono::Utc;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 
+mod attach;
+mod config;
+mod conversation;
+mod exec;
+mod facts;
+mod fix;
+mod lint;
+mod project;
+mod retry;
+mod safety;
+mod shell;
+mod tools;
+mod workspace;
+
+use config::{Checks, Config, ConfigChain, ConfigFile, InteractiveSettings, MissingTools, Provider};
+use conversation::{Conversation, Role};
+use facts::SystemFacts;
+use project::ProjectConfig;
+use safety::Risk;
+use exec::{Execution, Session};
+use workspace::Workspace;
+
+/// Exit status of `--raw` for a high-risk command, so the shell wrapper can refuse it
+const EXIT_DANGEROUS: i32 = 3;
+
+/// A breakdown of every segment and flag needs more room than a single command.
+const BREAKDOWN_TOKEN_FACTOR: u32 = 4;
+
+/// How much of a command's output interactive mode keeps in the conversation, from the end.
+const REPORTED_OUTPUT: usize = 1000;
 
 /// WTF (Write The Formula) - Translate natural language to shell commands using AI
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, disable_help_subcommand = true)]
 struct Args {
     /// The natural language prompt describing what you want to do
     #[arg(required = false)]
@@ -42,6 +72,71 @@ struct Args {
     /// Start interactive mode (REPL)
     #[arg(short, long)]
     interactive: bool,
+
+    /// Config profile to use from ~/.config/wtf/config.toml (or set WTF_PROFILE)
+    #[arg(short, long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    /// Print the effective system prompt (including .wtf.toml additions) and exit
+    #[arg(long)]
+    print_prompt: bool,
+
+    /// List the models installed on the configured Ollama server
+    #[arg(long)]
+    list_models: bool,
+
+    /// Show extra details, such as which provider answered
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Output the full result (command, explanation, risk, requirements, placeholders) as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Ask for N alternative commands and pick one (with --raw, print them one per line)
+    #[arg(
+        short = 'n',
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=10)
+    )]
+    candidates: usize,
+
+    /// With --raw and -n, show the picker and output only the chosen command
+    #[arg(long)]
+    pick: bool,
+
+    /// Include an excerpt of a text file in the request (repeatable; `@path` in the prompt works too)
+    #[arg(short = 'f', long = "file", value_name = "PATH")]
+    files: Vec<PathBuf>,
+
+    /// Let --raw output high-risk commands without failing (exit status 3 otherwise)
+    #[arg(long)]
+    allow_dangerous: bool,
+
+    /// Tell the model about the current directory: its files, project type and git state
+    #[arg(short, long)]
+    workspace: bool,
+
+    /// Don't tell the model about this system or the current directory
+    #[arg(long, conflicts_with = "workspace")]
+    no_context: bool,
+
+    #[command(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Explain an existing command, stage by stage and flag by flag (or pipe it in)
+    Explain {
+        /// The command to explain. Quote it so your shell leaves its quotes and operators alone.
+        #[arg(trailing_var_arg = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+    /// Fix the last command that failed in this shell (needs the shell integration)
+    Fix,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -49,13 +144,171 @@ struct HistoryEntry {
     timestamp: i64,
     prompt: String,
     command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provider: Option<String>,
 }
 
+#[derive(Serialize, Default)]
 struct CommandResult {
     command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     explanation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    risk: Option<Risk>,
+    /// Dangerous patterns found by the local safety check
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    /// Programs the command runs that are not installed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing: Vec<String>,
+    /// What the shell's syntax check reported, if the model couldn't fix it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    syntax_error: Option<String>,
+    /// Lint findings such as unquoted expansions
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    lints: Vec<lint::Finding>,
+    /// Syntax errors in earlier answers that were sent back to the model
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    repairs: Vec<String>,
+    /// Programs the command invokes
+    requires: Vec<String>,
+    /// Values the user has to fill in before running the command
+    placeholders: Vec<Placeholder>,
+    /// How this alternative differs from the others, when several were requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    /// Label of the provider that answered (see `Config::label`)
+    provider: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Placeholder {
+    name: String,
+    #[serde(default)]
+    description: String,
+}
+
+/// A non-success HTTP response from a provider.
+#[derive(Debug)]
+struct ApiError {
+    api: &'static str,
+    status: reqwest::StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(api: &'static str, status: reqwest::StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            api,
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error ({}): {}", self.api, self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl CommandResult {
+    /// Run the local safety check and raise the model's risk estimate to match.
+    /// Returns the locally detected risk, which is what gates execution.
+    fn check_safety(&mut self) -> Risk {
+        let assessment = safety::assess(&self.command);
+        self.risk = self.risk.max(Some(assessment.risk));
+        self.warnings = assessment.reasons;
+        assessment.risk
+    }
+
+    fn check_tools(&mut self, checks: &Checks) {
+        if checks.missing_tools != MissingTools::Off {
+            self.missing = tools::missing_programs(&self.command);
+        }
+    }
+
+    fn check_lint(&mut self, checks: &Checks) {
+        self.lints = lint::lint(&self.command, |rule| checks.lint_enabled(rule));
+    }
+}
+
+/// Everything a provider needs for one generation.
+struct GenerationRequest<'a> {
+    system_prompt: &'a str,
+    prompt: &'a str,
+    /// Earlier turns of an interactive session, sent ahead of `prompt`
+    history: Option<&'a Conversation>,
+    /// Number of alternative commands requested; 1 for a single command
+    candidates: usize,
+    format: ReplyFormat,
+}
+
+/// What the reply holds, which decides the response schema.
+#[derive(Clone, Copy, PartialEq)]
+enum ReplyFormat {
+    /// A command for the user's request (`StructuredOutput`)
+    Command,
+    /// An explanation of a command the user gave (`Breakdown`)
+    Breakdown,
+}
+
+impl<'a> GenerationRequest<'a> {
+    fn new(system_prompt: &'a str, prompt: &'a str) -> Self {
+        GenerationRequest {
+            system_prompt,
+            prompt,
+            history: None,
+            candidates: 1,
+            format: ReplyFormat::Command,
+        }
+    }
+
+    /// The profile's token limit covers one command, so scale it for alternatives
+    /// and for explanations of every flag.
+    fn max_tokens(&self, config: &Config) -> u32 {
+        match self.format {
+            ReplyFormat::Command => config.max_tokens.saturating_mul(self.candidates as u32),
+            ReplyFormat::Breakdown => config.max_tokens.saturating_mul(BREAKDOWN_TOKEN_FACTOR),
+        }
+    }
+
+    /// The conversation so far and the prompt, in order, starting with the user.
+    fn messages(&self) -> Vec<(Role, String)> {
+        match self.history {
+            Some(history) => history.messages(self.prompt),
+            None => vec![(Role::User, self.prompt.to_string())],
+        }
+    }
+
+    /// The messages as OpenAI, Anthropic and Ollama take them.
+    fn chat_messages(&self) -> Vec<Message> {
+        self.messages()
+            .into_iter()
+            .map(|(role, content)| Message {
+                role: match role {
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                }
+                .to_string(),
+                content,
+            })
+            .collect()
+    }
+
+    fn response_schema(&self, dialect: SchemaDialect) -> serde_json::Value {
+        match self.format {
+            ReplyFormat::Command => response_schema(dialect, self.candidates),
+            ReplyFormat::Breakdown => breakdown_schema(dialect),
+        }
+    }
 }
 
+/// Receives response text as it streams in. Providers that can't stream never call it.
+type TokenSink<'a> = Option<&'a mut dyn FnMut(&str)>;
+
 // ─────────────────────────────────────────────────────────────────────────────
 // OpenAI-compatible API structures (works with OpenRouter, Azure, Ollama, etc.)
 // ─────────────────────────────────────────────────────────────────────────────
@@ -65,6 +318,12 @@ struct OpenAIRequest {
     model: String,
     messages: Vec<Message>,
     max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
 }
 
 #[derive(Serialize)]
@@ -94,6 +353,105 @@ struct OpenAIError {
     message: String,
 }
 
+#[derive(Deserialize)]
+struct OpenAIStreamChunk {
+    choices: Option<Vec<StreamChoice>>,
+    error: Option<OpenAIError>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Anthropic Messages API structures
+// ─────────────────────────────────────────────────────────────────────────────
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+#[derive(Serialize)]
+struct AnthropicRequest {
+    model: String,
+    max_tokens: u32,
+    system: String,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicResponse {
+    content: Option<Vec<AnthropicContentBlock>>,
+    error: Option<AnthropicError>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicContentBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicError {
+    message: String,
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Ollama API structures
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[derive(Serialize)]
+struct OllamaRequest {
+    model: String,
+    messages: Vec<Message>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+    options: OllamaOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+    num_predict: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    message: Option<MessageContent>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OllamaTags {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct OllamaPullStatus {
+    status: Option<String>,
+    total: Option<u64>,
+    completed: Option<u64>,
+    error: Option<String>,
+}
+
 // ─────────────────────────────────────────────────────────────────────────────
 // Gemini API structures (for backwards compatibility)
 // ─────────────────────────────────────────────────────────────────────────────
@@ -103,15 +461,35 @@ struct GeminiRequest {
     contents: Vec<GeminiContent>,
     #[serde(rename = "systemInstruction")]
     system_instruction: GeminiContent,
+    #[serde(rename = "generationConfig")]
+    generation_config: GenerationConfig,
+}
+
+#[derive(Serialize)]
+struct GenerationConfig {
+    #[serde(rename = "maxOutputTokens")]
+    max_output_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(rename = "responseMimeType", skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<String>,
+    #[serde(rename = "responseSchema", skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct GeminiContent {
+    /// "user" or "model"; left out of the system instruction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    // The final chunk of a stream can carry a candidate without parts
+    #[serde(default)]
     parts: Vec<Part>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Part {
+    #[serde(default)]
     text: String,
 }
 
@@ -131,118 +509,147 @@ struct GeminiError {
     message: String,
 }
 
-// ─────────────────────────────────────────────────────────────────────────────
-// Configuration
-// ─────────────────────────────────────────────────────────────────────────────
-
-struct Config {
-    api_key: String,
-    base_url: String,
-    model: String,
-    provider: Provider,
-}
-
-#[derive(PartialEq)]
-enum Provider {
-    Gemini,
-    OpenAI, // OpenAI-compatible (OpenRouter, Azure, Ollama, etc.)
-}
-
-impl Config {
-    fn from_env() -> Result<Self> {
-        // Try WTF_API_KEY first, then fall back to GEMINI_API_KEY
-        let api_key = env::var("WTF_API_KEY")
-            .or_else(|_| env::var("GEMINI_API_KEY"))
-            .context(
-                "API key not set.\n\n\
-                Set one of these environment variables:\n\
-                  export WTF_API_KEY='your-key'      # For any provider\n\
-                  export GEMINI_API_KEY='your-key'   # For Gemini (legacy)\n\n\
-                Get a free Gemini key at: https://aistudio.google.com/app/apikey"
-            )?;
-
-        let base_url = env::var("WTF_BASE_URL").unwrap_or_default();
-        let model = env::var("WTF_MODEL").unwrap_or_default();
-
-        // Determine provider based on base_url
-        let (provider, base_url, model) = if base_url.is_empty() {
-            // Default to Gemini
-            (
-                Provider::Gemini,
-                "https://generativelanguage.googleapis.com/v1beta".to_string(),
-                if model.is_empty() { "gemini-2.0-flash".to_string() } else { model },
-            )
-        } else {
-            // Custom base URL = OpenAI-compatible
-            (
-                Provider::OpenAI,
-                base_url,
-                if model.is_empty() { "gpt-4o-mini".to_string() } else { model },
-            )
-        };
+const SYSTEM_PROMPT: &str = r#"You are a shell command expert. Your task is to translate the user's natural language request into a valid shell command.
 
-        Ok(Config {
-            api_key,
-            base_url,
-            model,
-            provider,
-        })
-    }
-}
+Respond with a single JSON object and nothing else. No markdown, no code blocks:
+{"command": "...", "explanation": "...", "risk": "low", "requires": ["..."], "placeholders": [{"name": "...", "description": "..."}]}
 
-const SYSTEM_PROMPT: &str = r#"You are a shell command expert. Your task is to translate the user's natural language request into a valid shell command.
+- command: the shell command
+- explanation: one short sentence describing what the command does
+- risk: "high" if it deletes data, changes system configuration or is otherwise hard to undo, "medium" if it modifies files or state, "low" if it only reads
+- requires: the programs the command invokes
+- placeholders: values in the command the user must fill in themselves, such as <file> or YOUR_TOKEN (empty if none)
 
 Rules:
-1. Output ONLY the shell command, nothing else. No explanations, no markdown, no code blocks.
-2. Use standard POSIX commands when possible for portability.
-3. For macOS-specific tasks, use the appropriate macOS commands.
-4. If the request is dangerous (like rm -rf /), still provide the command but add a comment warning.
-5. If the request is ambiguous, provide the most common interpretation.
-6. Use single quotes for strings unless double quotes are necessary for variable expansion.
-7. For multi-step operations, chain commands with && or use a single-line script.
+1. Use standard POSIX commands when possible for portability.
+2. Match the user's system when it is described below (GNU or BSD flags, package manager). For macOS-specific tasks, use the appropriate macOS commands.
+3. If the request is dangerous (like rm -rf /), still provide the command but set risk to "high" and warn in the explanation.
+4. If the request is ambiguous, provide the most common interpretation.
+5. Use single quotes for strings unless double quotes are necessary for variable expansion.
+6. For multi-step operations, chain commands with && or use a single-line script.
 
 Examples:
 User: show my ip address
-Output: curl -s ifconfig.me
+Output: {"command": "curl -s ifconfig.me", "explanation": "Prints your public IP address.", "risk": "low", "requires": ["curl"], "placeholders": []}
 
 User: find large files over 100mb
-Output: find . -type f -size +100M
+Output: {"command": "find . -type f -size +100M", "explanation": "Lists files under the current directory larger than 100MB.", "risk": "low", "requires": ["find"], "placeholders": []}
 
 User: kill process on port 3000
-Output: lsof -ti:3000 | xargs kill -9
+Output: {"command": "lsof -ti:3000 | xargs kill -9", "explanation": "Force-kills whatever process is listening on port 3000.", "risk": "medium", "requires": ["lsof", "xargs", "kill"], "placeholders": []}
+
+User: copy a file to my server
+Output: {"command": "scp <file> <user>@<host>:~/", "explanation": "Copies a file to your home directory on a remote host over SSH.", "risk": "low", "requires": ["scp"], "placeholders": [{"name": "<file>", "description": "file to copy"}, {"name": "<user>", "description": "your user on the server"}, {"name": "<host>", "description": "server hostname"}]}
+"#;
+
+const SYSTEM_PROMPT_EXPLAIN: &str = r#"
+The user asked for an explanation: make "explanation" a concise walkthrough of what each part of the command does.
+"#;
 
-User: compress this folder
-Output: tar -czvf archive.tar.gz .
+const SYSTEM_PROMPT_CANDIDATES: &str = r#"
+The user asked for {n} alternatives: respond with {"candidates": [...]} holding {n} objects in the format above, most recommended first. Give each one an extra "note" field saying in a few words how it differs from the others, such as a different tool or a safer variant.
 "#;
 
-const SYSTEM_PROMPT_EXPLAIN: &str = r#"You are a shell command expert.
-1. Output the shell command.
-2. Output a separator: " ### "
-3. Output a concise explanation of what the command does.
+const SYSTEM_PROMPT_BREAKDOWN: &str = r#"You are a shell command expert. The user gives you an existing shell command: explain it, do not write a new one.
+
+Respond with a single JSON object and nothing else. No markdown, no code blocks:
+{"summary": "...", "segments": [{"text": "...", "program": "...", "description": "...", "options": [{"option": "...", "meaning": "..."}]}]}
+
+- summary: one or two sentences on what the whole command does, including anything destructive or surprising
+- segments: one per pipeline stage or command joined by |, &&, || or ;, in order
+- text: the segment exactly as written
+- program: the program it runs
+- description: what this segment does with its input and arguments
+- options: every option and argument in the order written, with values kept together with their option (e.g. "-C /tmp"), and what each one means here
 
 Example:
-User: list files
-Output: ls -la ### Lists all files including hidden ones in long format.
+User: ps aux | grep -v grep | grep -i nginx
+Output: {"summary": "Lists running processes whose command line mentions nginx, case-insensitively.", "segments": [{"text": "ps aux", "program": "ps", "description": "Lists every process on the system.", "options": [{"option": "a", "meaning": "processes of all users"}, {"option": "u", "meaning": "user-oriented format with owner, CPU and memory"}, {"option": "x", "meaning": "include processes without a terminal"}]}, {"text": "grep -v grep", "program": "grep", "description": "Drops the grep process itself from the list.", "options": [{"option": "-v", "meaning": "keep lines that do not match"}, {"option": "grep", "meaning": "the pattern"}]}, {"text": "grep -i nginx", "program": "grep", "description": "Keeps the lines about nginx.", "options": [{"option": "-i", "meaning": "ignore case"}, {"option": "nginx", "meaning": "the pattern"}]}]}
 "#;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    // The shell wrapper shows "⏳ Generating..." on stderr while it captures stdout, so
+    // raw-mode errors replace that line instead of being appended to it
+    if args.raw {
+        if let Err(e) = run(args).await {
+            eprintln!("\r\x1b[K❌ {:#}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    run(args).await
+}
+
+async fn run(args: Args) -> Result<()> {
     // Handle --init flag for shell integration
     if let Some(shell) = &args.init {
         print_init_script(shell);
         return Ok(());
     }
 
+    let cwd = env::current_dir()?;
+    let file = ConfigFile::load()?;
+    let settings = &file.context;
+    let context = PromptContext {
+        // Project-local .wtf.toml, searched upwards from the current directory
+        project: ProjectConfig::discover(&cwd)?,
+        system: (settings.system && !args.no_context).then(SystemFacts::load),
+        workspace: ((settings.workspace || args.workspace) && !args.no_context).then(|| Workspace::collect(&cwd)),
+        cwd: None,
+        variables: Vec::new(),
+    };
+
+    if args.print_prompt {
+        print!("{}", build_system_prompt(args.explain, args.candidates, &context));
+        return Ok(());
+    }
+
+    if args.list_models {
+        let configs = Config::load_chain(&file, args.profile.as_deref())?;
+        return list_models(configs.primary()).await;
+    }
+
     // Handle interactive mode
     if args.interactive {
-        let config = Config::from_env()?;
-        return run_interactive_mode(&config, args.explain).await;
+        let configs = Config::load_chain(&file, args.profile.as_deref())?;
+        return run_interactive_mode(&configs, &file.checks, &file.interactive, context, args.explain, args.verbose, args.candidates)
+            .await;
     }
 
+    // `kubectl get pods | wtf "…"` sends the pods along; `echo "…" | wtf` is the prompt
+    let piped = if args.history || args.clear_history { None } else { attach::read_stdin()? };
+
+    // `wtf explain '<command>'`, or a command piped to `wtf explain`
+    if let Some(Action::Explain { command }) = &args.action {
+        let command = match command.as_slice() {
+            [] => piped.map(attach::Piped::into_prompt),
+            words => Some(words.join(" ")),
+        };
+        let Some(command) = command.filter(|command| !command.trim().is_empty()) else {
+            anyhow::bail!("Nothing to explain. Usage: wtf explain '<command>', or <command> | wtf explain");
+        };
+        let configs = Config::load_chain(&file, args.profile.as_deref())?;
+        return explain_command(&args, &configs, &context, &command).await;
+    }
+
+    let (prompt, piped) = match piped {
+        Some(input) if args.prompt.is_empty() => (input.into_prompt(), None),
+        piped => (args.prompt.join(" "), piped),
+    };
+
+    // `wtf fix`: correct the last command that failed in this shell
+    let fixing = if matches!(args.action, Some(Action::Fix)) { Some(prepare_fix()?) } else { None };
+    let prompt = match &fixing {
+        Some(last) => format!("fix: {}", last.command),
+        None => prompt,
+    };
+
     // Check if prompt is provided
-    if args.prompt.is_empty() {
+    if prompt.is_empty() {
         if args.clear_history {
             clear_history()?;
             return Ok(());
@@ -254,253 +661,1609 @@ async fn main() -> Result<()> {
 
         eprintln!("Usage: wtf <natural language prompt>");
         eprintln!("       wtf --interactive  # Start interactive mode");
+        eprintln!("       <command> | wtf <prompt>  # Use the piped output as context");
+        eprintln!("       wtf explain '<command>'  # Explain an existing command");
+        eprintln!("       wtf fix  # Fix the last command that failed");
         eprintln!("       eval \"$(command wtf --init zsh)\"");
         eprintln!("\nExample: wtf show my ip address");
         std::process::exit(1);
     }
 
-    let prompt = args.prompt.join(" ");
-    let config = Config::from_env()?;
+    let (request_prompt, attachments) = match &fixing {
+        Some(last) => (last.prompt(), Vec::new()),
+        None => attach::attach(&prompt, &args.files, piped)?,
+    };
+    if args.verbose {
+        for attachment in &attachments {
+            eprintln!("\x1b[90m📎 {}\x1b[0m", attachment.summary());
+        }
+    }
+    let configs = Config::load_chain(&file, args.profile.as_deref())?;
+    let checks = &file.checks;
+
+    let system_prompt = build_system_prompt(args.explain, args.candidates, &context);
+
+    if args.candidates > 1 {
+        let request = GenerationRequest {
+            candidates: args.candidates,
+            ..GenerationRequest::new(&system_prompt, &request_prompt)
+        };
+        return run_candidates(&args, &configs, checks, &prompt, &request).await;
+    }
+
+    // Raw output is consumed by the shell wrapper, so only stream when a person is watching
+    let request = GenerationRequest::new(&system_prompt, &request_prompt);
+    let mut preview = CommandPreview::default();
+    let result = if args.raw || args.json {
+        get_command(&configs, &request, None).await
+    } else {
+        get_command(&configs, &request, Some(&mut |token: &str| preview.push(token))).await
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            preview.abort();
+            return Err(e);
+        }
+    };
+
+    let streamed = !(args.raw || args.json);
+    let mut result = review_command(&configs, checks, &request, result, streamed.then_some(&mut preview)).await;
+    result.check_safety();
+    let command = result.command.as_str();
 
-    let result = get_command(&config, &prompt, args.explain).await?;
-    
-    // Strip markdown code blocks if present in command
-    let command = result.command
-        .trim()
-        .trim_start_matches("```bash")
-        .trim_start_matches("```sh")
-        .trim_start_matches("```")
-        .trim_end_matches("```")
-        .trim();
-        
     // Save to history
-    if let Err(e) = append_to_history(&prompt, command) {
+    if let Err(e) = append_to_history(&prompt, command, Some(&result.provider)) {
         eprintln!("Warning: Failed to save history: {}", e);
     }
 
+    if args.json {
+        println!("{}", serde_json::to_string(&result)?);
+        return Ok(());
+    }
+
     // Raw mode: just output the command (for shell wrapper)
     if args.raw {
-        println!("{}", command);
-        return Ok(());
+        return print_raw(&args, &result);
     }
 
     // Default mode: show command with emoji
-    println!("💡 \x1b[36m{}\x1b[0m", command);
-    
-    if let Some(explanation) = result.explanation {
-        println!("\x1b[90m📝 {}\x1b[0m", explanation.trim());
-    }
+    preview.finish(command, risk_badge(result.risk));
+    print_command_details(&result, args.explain, args.verbose);
 
     Ok(())
 }
 
-fn print_init_script(shell: &str) {
-    match shell {
-        "zsh" => {
-            print!(r#"# WTF (Write The Formula) - Shell integration
-# Add to ~/.zshrc: eval "$(command wtf --init zsh)"
+/// Check a freshly generated command and have the model fix what it can: programs
+/// that aren't installed (with `missing_tools = "retry"`) and syntax errors. `preview`
+/// is the line the answer streamed into, when a person is watching.
+async fn review_command(
+    configs: &ConfigChain<'_>,
+    checks: &Checks,
+    request: &GenerationRequest<'_>,
+    mut result: CommandResult,
+    mut preview: Option<&mut CommandPreview>,
+) -> CommandResult {
+    result.command = clean_command(&result.command);
+    result.check_tools(checks);
+
+    if !result.missing.is_empty() && checks.missing_tools == MissingTools::Retry {
+        let missing = result.missing.join(", ");
+        announce(
+            preview.as_deref_mut(),
+            &result,
+            &format!("\x1b[33m❓ Not installed: {}\x1b[0m \x1b[90m- asking for an alternative...\x1b[0m", missing),
+        );
+        let feedback = format!(
+            "These programs are not installed here: {}. Give a command that works without them.",
+            missing
+        );
+        if let Some(alternative) = regenerate(configs, checks, request, &result, &feedback, preview.as_deref_mut()).await {
+            result = alternative;
+        }
+    }
 
-function wtf() {{
-    if [[ -z "$1" ]]; then
-        echo "Usage: wtf <natural language prompt>"
-        return 1
-    fi
+    let shell = shell::target_shell();
+    let mut attempt = 0;
+    while let Err(error) = shell::check_syntax(&result.command) {
+        if attempt == checks.syntax_repairs {
+            result.syntax_error = Some(error);
+            break;
+        }
+        attempt += 1;
+        announce(
+            preview.as_deref_mut(),
+            &result,
+            &format!(
+                "\x1b[33m🔧 Syntax error, asking for a fix ({}/{}): {}\x1b[0m",
+                attempt,
+                checks.syntax_repairs,
+                error.lines().next().unwrap_or_default()
+            ),
+        );
+        let feedback = format!("It fails the {} syntax check:\n{}\nFix the syntax.", shell, error);
+        match regenerate(configs, checks, request, &result, &feedback, preview.as_deref_mut()).await {
+            Some(fixed) => {
+                result = fixed;
+                result.repairs.push(error);
+            }
+            None => {
+                result.syntax_error = Some(error);
+                break;
+            }
+        }
+    }
 
-    # Show loading state
-    echo -n "⏳ Generating..." >&2
+    result.check_lint(checks);
+    result
+}
 
-    local cmd
-    # Use the binary to get the command (raw mode)
-    cmd=$(command wtf --raw "$@" 2>&1)
-    local exit_code=$?
+/// Show why the model is being asked again, after the answer it gave so far.
+fn announce(preview: Option<&mut CommandPreview>, result: &CommandResult, message: &str) {
+    match preview {
+        Some(preview) => {
+            preview.finish(&result.command, "");
+            println!("{}", message);
+            *preview = CommandPreview::default();
+        }
+        // Raw and JSON output belong to the caller, so report on stderr
+        None => eprintln!("\r\x1b[K{}", message),
+    }
+}
 
-    # Clear loading state (CR + Clear Line)
-    echo -ne "\r\033[K" >&2
+/// Ask again with `feedback` about the previous answer. Keeps the previous answer if that fails.
+async fn regenerate(
+    configs: &ConfigChain<'_>,
+    checks: &Checks,
+    request: &GenerationRequest<'_>,
+    previous: &CommandResult,
+    feedback: &str,
+    preview: Option<&mut CommandPreview>,
+) -> Option<CommandResult> {
+    let prompt = format!(
+        "{}\n\nYour previous answer was: {}\n{}",
+        request.prompt, previous.command, feedback
+    );
+    // Same system prompt, so the same number of commands and reply format
+    let retry = GenerationRequest {
+        prompt: &prompt,
+        ..*request
+    };
 
-    if [[ $exit_code -ne 0 ]]; then
-        echo "❌ $cmd"
-        return 1
-    fi
+    let retried = match preview {
+        Some(preview) => {
+            let retried = get_command(configs, &retry, Some(&mut |token: &str| preview.push(token))).await;
+            if retried.is_err() {
+                preview.abort();
+            }
+            retried
+        }
+        None => get_command(configs, &retry, None).await,
+    };
 
-    # Show the command with formatting
-    echo "💡 \033[36m$cmd\033[0m"
-    echo ""
-    
-    # Put in buffer (print -z)
-    print -z "$cmd"
-}}
+    match retried {
+        Ok(mut result) => {
+            result.command = clean_command(&result.command);
+            result.check_tools(checks);
+            Some(result)
+        }
+        Err(e) => {
+            eprintln!("\x1b[33m⚠️  Could not get a new answer: {}\x1b[0m", e);
+            None
+        }
+    }
+}
 
-alias '??'='wtf'
-"#);
+/// Explain on stderr why a `--raw` command is withheld from the buffer, then exit.
+fn refuse_dangerous(result: &CommandResult) -> ! {
+    eprintln!("\r\x1b[K\x1b[1;31m⛔ Not inserting a high-risk command:\x1b[0m {}", result.command);
+    for warning in &result.warnings {
+        eprintln!("\x1b[31m⚠️  {}\x1b[0m", warning);
+    }
+    eprintln!("\x1b[90mRun it with --allow-dangerous to insert it anyway.\x1b[0m");
+    std::process::exit(EXIT_DANGEROUS);
+}
+
+fn risk_badge(risk: Option<Risk>) -> &'static str {
+    match risk {
+        Some(Risk::High) => "  \x1b[1;31m[high risk]\x1b[0m",
+        Some(Risk::Medium) => "  \x1b[33m[medium risk]\x1b[0m",
+        _ => "",
+    }
+}
+
+/// Handle `-n`: print every candidate for scripts, or let the user pick one.
+async fn run_candidates(
+    args: &Args,
+    configs: &ConfigChain<'_>,
+    checks: &Checks,
+    prompt: &str,
+    request: &GenerationRequest<'_>,
+) -> Result<()> {
+    let mut candidates = get_candidates(configs, request).await?;
+    for candidate in &mut candidates {
+        candidate.command = clean_command(&candidate.command);
+        candidate.check_safety();
+        candidate.check_tools(checks);
+        candidate.syntax_error = shell::check_syntax(&candidate.command).err();
+        candidate.check_lint(checks);
+    }
+    candidates.retain(|c| !c.command.is_empty());
+    if candidates.is_empty() {
+        anyhow::bail!("The model returned no commands");
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string(&candidates)?);
+        return Ok(());
+    }
+
+    let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+    if args.raw && !args.pick {
+        // One high-risk candidate withholds them all, as it would a single command
+        if !args.allow_dangerous
+            && let Some(dangerous) = candidates.iter().find(|c| safety::assess(&c.command).risk == Risk::High)
+        {
+            refuse_dangerous(dangerous);
         }
-        "bash" => {
-            print!(r#"# WTF (Write The Formula) - Shell integration
-# Add to ~/.bashrc: eval "$(wtf --init bash)"
+        for candidate in &candidates {
+            println!("{}", candidate.command);
+        }
+        return Ok(());
+    }
+
+    if !interactive {
+        // Nobody to ask: the shell wrapper gets the most recommended one
+        if args.raw {
+            return print_raw(args, &candidates[0]);
+        }
+        for candidate in &candidates {
+            println!("💡 \x1b[36m{}\x1b[0m{}", candidate.command, risk_badge(candidate.risk));
+            if let Some(note) = &candidate.note {
+                println!("\x1b[90m   {}\x1b[0m", note);
+            }
+        }
+        return Ok(());
+    }
+
+    let result = pick_candidate(candidates)?.context("No command selected")?;
+    if let Err(e) = append_to_history(prompt, &result.command, Some(&result.provider)) {
+        eprintln!("Warning: Failed to save history: {}", e);
+    }
+
+    if args.raw {
+        return print_raw(args, &result);
+    }
+    println!("💡 \x1b[36m{}\x1b[0m{}", result.command, risk_badge(result.risk));
+    print_command_details(&result, args.explain, args.verbose);
+    Ok(())
+}
+
+/// Handle `wtf explain '<command>'`: break an existing command down segment by
+/// segment and flag by flag, without generating a new one.
+async fn explain_command(args: &Args, configs: &ConfigChain<'_>, context: &PromptContext, command: &str) -> Result<()> {
+    let mut system_prompt = SYSTEM_PROMPT_BREAKDOWN.to_string();
+    if let Some(system) = &context.system {
+        system_prompt.push_str(&system.prompt_section());
+    }
+    let request = GenerationRequest {
+        format: ReplyFormat::Breakdown,
+        ..GenerationRequest::new(&system_prompt, command)
+    };
+
+    let waiting = !args.json && io::stderr().is_terminal();
+    if waiting {
+        eprint!("\x1b[90m⏳ Explaining...\x1b[0m\r");
+    }
+    let reply = generate(configs, &request, None).await;
+    if waiting {
+        eprint!("\r\x1b[K");
+    }
+    let (text, provider) = reply?;
+
+    let breakdown = serde_json::from_str::<Breakdown>(strip_json_fence(&text)).unwrap_or_else(|_| Breakdown {
+        // The model ignored the JSON instructions: its reply is the explanation
+        summary: text.trim().to_string(),
+        segments: Vec::new(),
+    });
+
+    if args.json {
+        println!("{}", serde_json::to_string(&breakdown)?);
+        return Ok(());
+    }
+
+    let assessment = safety::assess(command);
+    println!("📖 \x1b[36m{}\x1b[0m{}", command, risk_badge(Some(assessment.risk)));
+    let color = if assessment.risk == Risk::High { "31" } else { "33" };
+    for reason in &assessment.reasons {
+        println!("\x1b[{}m⚠️  {}\x1b[0m", color, reason);
+    }
+    println!("\n{}", breakdown.summary);
+
+    for (index, segment) in breakdown.segments.iter().enumerate() {
+        println!("\n\x1b[1m{}. {}\x1b[0m", index + 1, segment.text);
+        if segment.program.is_empty() {
+            println!("   {}", segment.description);
+        } else {
+            println!("   \x1b[36m{}\x1b[0m: {}", segment.program, segment.description);
+        }
+        let width = segment.options.iter().map(|o| o.option.chars().count()).max().unwrap_or(0);
+        for option in &segment.options {
+            println!("     \x1b[33m{:<width$}\x1b[0m  {}", option.option, option.meaning, width = width);
+        }
+    }
+
+    if args.verbose {
+        println!("\n\x1b[90m🤖 {}\x1b[0m", provider);
+    }
+    Ok(())
+}
+
+/// Load the last command for `wtf fix` and, if the user agrees, run it again to
+/// capture its error output. Only low risk commands whose every part the safety
+/// check can see are offered a re-run, and low risk still covers `git commit`, `mv`
+/// and the like, so the prompt warns that it really runs again.
+fn prepare_fix() -> Result<fix::LastCommand> {
+    let mut last = fix::LastCommand::load()?;
+    if last.exit_status == 0 {
+        anyhow::bail!("The last command succeeded: {}", last.command);
+    }
+    eprintln!(
+        "\r\x1b[K\x1b[90m↩️  {}  (exit status {})\x1b[0m",
+        last.command, last.exit_status
+    );
+
+    if !(io::stdin().is_terminal() && io::stderr().is_terminal()) {
+        return Ok(last);
+    }
+    if safety::assess(&last.command).risk != Risk::Low {
+        eprintln!("\x1b[90mNot re-running it to see the error, since it could do damage.\x1b[0m");
+        return Ok(last);
+    }
+    if safety::runs_unchecked_code(&last.command) {
+        eprintln!("\x1b[90mNot re-running it to see the error, since part of what it runs can't be checked first.\x1b[0m");
+        return Ok(last);
+    }
+
+    eprint!("\x1b[90mRe-run it to capture its error output? It runs for real, side effects included (y/N): \x1b[0m");
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        last.rerun()?;
+    }
+    Ok(last)
+}
+
+/// Print the command for the shell wrapper, with anything worth knowing about it on stderr.
+fn print_raw(args: &Args, result: &CommandResult) -> Result<()> {
+    // Checked first, so a caller reading only stdout never gets a refused command
+    if safety::assess(&result.command).risk == Risk::High && !args.allow_dangerous {
+        refuse_dangerous(result);
+    }
+    println!("{}", result.command);
+    if !result.missing.is_empty() {
+        eprintln!("\r\x1b[K\x1b[33m❓ Not installed: {}\x1b[0m", result.missing.join(", "));
+    }
+    if let Some(error) = &result.syntax_error {
+        eprintln!("\r\x1b[K\x1b[31m❌ Syntax error: {}\x1b[0m", error);
+    }
+    for finding in &result.lints {
+        eprintln!("\r\x1b[K\x1b[36m🔍 {}: {}\x1b[0m", finding.rule, finding.message);
+    }
+    Ok(())
+}
+
+/// Arrow-key menu on stderr, so it also works while stdout is captured by the shell
+/// wrapper. Returns `None` if the user backs out with Esc or q.
+fn pick_candidate(mut candidates: Vec<CommandResult>) -> Result<Option<CommandResult>> {
+    if candidates.len() <= 1 {
+        return Ok(candidates.pop());
+    }
+
+    let items: Vec<String> = candidates
+        .iter()
+        .map(|c| {
+            let mut item = format!("{}{}", c.command, risk_badge(c.risk));
+            if !c.missing.is_empty() {
+                item.push_str(&format!("  \x1b[33m[not installed: {}]\x1b[0m", c.missing.join(", ")));
+            }
+            if c.syntax_error.is_some() {
+                item.push_str("  \x1b[31m[syntax error]\x1b[0m");
+            }
+            if let Some(note) = &c.note {
+                item.push_str(&format!("  \x1b[90m# {}\x1b[0m", note));
+            }
+            item
+        })
+        .collect();
+
+    // Replace the "⏳ Generating..." line
+    eprint!("\r\x1b[K");
+    let choice = dialoguer::Select::with_theme(&dialoguer::theme::ColorfulTheme::default())
+        .with_prompt("Pick a command")
+        .items(&items)
+        .default(0)
+        .report(false)
+        .interact_opt()
+        .context("Failed to show the command picker")?;
+
+    Ok(choice.map(|index| candidates.swap_remove(index)))
+}
+
+/// Print what we know about a generated command below its 💡 line.
+fn print_command_details(result: &CommandResult, explain: bool, verbose: bool) {
+    let color = if result.risk == Some(Risk::High) { "31" } else { "33" };
+    for warning in &result.warnings {
+        println!("\x1b[{}m⚠️  {}\x1b[0m", color, warning);
+    }
+
+    if !result.missing.is_empty() {
+        println!("\x1b[33m❓ Not installed: {}\x1b[0m", result.missing.join(", "));
+    }
+
+    if let Some(error) = &result.syntax_error {
+        println!("\x1b[31m❌ Syntax error: {}\x1b[0m", error);
+    }
+
+    for finding in &result.lints {
+        println!("\x1b[36m🔍 {}: {}\x1b[0m", finding.rule, finding.message);
+        if explain {
+            println!("\x1b[90m   {}\x1b[0m", finding.help());
+        }
+    }
+
+    for placeholder in &result.placeholders {
+        if placeholder.description.is_empty() {
+            println!("\x1b[33m✏️  Replace {}\x1b[0m", placeholder.name);
+        } else {
+            println!("\x1b[33m✏️  Replace {} ({})\x1b[0m", placeholder.name, placeholder.description);
+        }
+    }
+
+    if explain && let Some(explanation) = &result.explanation {
+        println!("\x1b[90m📝 {}\x1b[0m", explanation);
+    }
+
+    if explain || verbose {
+        if !result.requires.is_empty() {
+            println!("\x1b[90m🔧 Requires: {}\x1b[0m", result.requires.join(", "));
+        }
+        println!("\x1b[90m🤖 {}\x1b[0m", result.provider);
+    }
+}
+
+fn print_init_script(shell: &str) {
+    match shell {
+        "zsh" => {
+            print!(r#"# WTF (Write The Formula) - Shell integration
+# Add to ~/.zshrc: eval "$(command wtf --init zsh)"
+
+function wtf() {{
+    if [[ -z "$1" ]]; then
+        echo "Usage: wtf <natural language prompt>"
+        return 1
+    fi
+
+    # `wtf explain` prints its answer instead of filling the buffer
+    if [[ "$1" == explain ]]; then
+        command wtf "$@"
+        return
+    fi
+
+    # Show loading state
+    echo -n "⏳ Generating..." >&2
+
+    local cmd
+    # Use the binary to get the command (raw mode). Errors and retry
+    # status go straight to stderr, only the command is captured.
+    # Aliases and functions are passed along so they count as installed.
+    cmd=$(WTF_SHELL_NAMES="${{(k)aliases}} ${{(k)functions}}" command wtf --raw --pick "$@")
+    local exit_code=$?
+
+    # Clear loading state (CR + Clear Line)
+    echo -ne "\r\033[K" >&2
+
+    # 3 means the command was judged high risk and is not inserted
+    if [[ $exit_code -ne 0 ]]; then
+        return $exit_code
+    fi
+
+    # Show the command with formatting
+    echo "💡 \033[36m$cmd\033[0m"
+    echo ""
+    
+    # Put in buffer (print -z)
+    print -z "$cmd"
+}}
+
+alias '??'='wtf'
+
+# Record each command's exit status, directory and text for `wtf fix`
+export WTF_SESSION=$$
+_wtf_state="${{XDG_STATE_HOME:-$HOME/.local/state}}/wtf/sessions"
+mkdir -p "$_wtf_state"
+
+_wtf_preexec() {{
+    _wtf_last_command=$1
+}}
+
+_wtf_precmd() {{
+    local exit_code=$?
+    [[ -n "$_wtf_last_command" ]] || return
+    # Keep the failed command while wtf itself runs
+    if [[ "$_wtf_last_command" != (wtf|\?\?)(| *) ]]; then
+        printf '%s\n%s\n%s\n' "$exit_code" "$PWD" "$_wtf_last_command" >| "$_wtf_state/$$"
+    fi
+    _wtf_last_command=
+}}
+
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec _wtf_preexec
+add-zsh-hook precmd _wtf_precmd
+"#);
+        }
+        "bash" => {
+            print!(r#"# WTF (Write The Formula) - Shell integration
+# Add to ~/.bashrc: eval "$(wtf --init bash)"
+
+function wtf() {{
+    if [[ -z "$1" ]]; then
+        echo "Usage: wtf <natural language prompt>"
+        return 1
+    fi
+
+    if [[ "$1" == explain ]]; then
+        command wtf "$@"
+        return
+    fi
+
+    echo -n "⏳ Generating..." >&2
+
+    local cmd
+    cmd=$(WTF_SHELL_NAMES="$(compgen -a; compgen -A function)" command wtf --raw --pick "$@")
+    local exit_code=$?
+
+    echo -ne "\r\033[K" >&2
+
+    if [[ $exit_code -ne 0 ]]; then
+        return $exit_code
+    fi
+
+    echo "💡 $cmd"
+    echo "📋 Copied to clipboard"
+    printf '%s' "$cmd" | pbcopy
+}}
+
+alias '??'='wtf'
+
+# Record each command's exit status, directory and text for `wtf fix`
+export WTF_SESSION=$$
+_wtf_state="${{XDG_STATE_HOME:-$HOME/.local/state}}/wtf/sessions"
+mkdir -p "$_wtf_state"
+_wtf_last_entry=$(HISTTIMEFORMAT= builtin history 1)
+
+# The first command run from the prompt, skipping the parts of PROMPT_COMMAND
+_wtf_preexec() {{
+    if [[ -n "$_wtf_at_prompt" && "$PROMPT_COMMAND" != *"$BASH_COMMAND"* ]]; then
+        _wtf_at_prompt=
+        _wtf_first=$BASH_COMMAND
+    fi
+}}
+
+_wtf_precmd() {{
+    local exit_code=$? entry first=$_wtf_first
+    _wtf_first=
+    entry=$(HISTTIMEFORMAT= builtin history 1)
+    if [[ "$entry" =~ ^\ *[0-9]+\*?\ +(.*)$ ]]; then
+        # An unchanged entry is the same command again under HISTCONTROL=ignoredups if
+        # that is what just started; otherwise an empty line or a command left out of history
+        if [[ "$entry" != "$_wtf_last_entry" || ( -n "$first" && "${{BASH_REMATCH[1]}} " == "$first"[[:space:]\;\|\&]* ) ]]; then
+            case "${{BASH_REMATCH[1]}}" in
+                wtf|wtf\ *|'??'|'?? '*) ;;
+                *) printf '%s\n%s\n%s\n' "$exit_code" "$PWD" "${{BASH_REMATCH[1]}}" >| "$_wtf_state/$$" ;;
+            esac
+        fi
+    fi
+    _wtf_last_entry=$entry
+    _wtf_at_prompt=1
+    return $exit_code
+}}
+
+# Someone else's DEBUG trap stays; without ours a repeated command only counts when
+# the history shows it
+[[ -n "$(trap -p DEBUG)" ]] || trap _wtf_preexec DEBUG
+PROMPT_COMMAND="_wtf_precmd${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}"
+"#);
+        }
+        _ => {
+            eprintln!("Unsupported shell: {}. Supported: zsh, bash", shell);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// What the system prompt says about where the command will run.
+struct PromptContext {
+    project: Option<ProjectConfig>,
+    /// `None` with `--no-context`
+    system: Option<SystemFacts>,
+    /// Only with `--workspace` or `[context] workspace = true`
+    workspace: Option<Workspace>,
+    /// Where interactive mode's shell is
+    cwd: Option<PathBuf>,
+    /// Environment variables interactive mode's shell has changed, by name
+    variables: Vec<String>,
+}
+
+fn build_system_prompt(explain: bool, candidates: usize, context: &PromptContext) -> String {
+    let mut system_prompt = SYSTEM_PROMPT.to_string();
+    if explain {
+        system_prompt.push_str(SYSTEM_PROMPT_EXPLAIN);
+    }
+    if candidates > 1 {
+        system_prompt.push_str(&SYSTEM_PROMPT_CANDIDATES.replace("{n}", &candidates.to_string()));
+    }
+    if let Some(system) = &context.system {
+        system_prompt.push_str(&system.prompt_section());
+    }
+    if let Some(workspace) = &context.workspace {
+        system_prompt.push_str(workspace.prompt_section());
+    } else if let Some(cwd) = &context.cwd {
+        system_prompt.push_str(&format!("\nCommands run in {}.\n", cwd.display()));
+    }
+    if !context.variables.is_empty() {
+        system_prompt.push_str(&format!(
+            "\nEarlier commands changed these environment variables: {}.\n",
+            context.variables.join(", ")
+        ));
+    }
+    if let Some(project) = &context.project {
+        system_prompt.push_str(&project.prompt_section());
+    }
+    system_prompt
+}
+
+async fn get_command(
+    configs: &ConfigChain<'_>,
+    request: &GenerationRequest<'_>,
+    on_token: TokenSink<'_>,
+) -> Result<CommandResult> {
+    let (text, provider) = generate(configs, request, on_token).await?;
+    Ok(CommandResult {
+        provider,
+        ..parse_output(&text)
+    })
+}
+
+/// Ask for `request.candidates` alternative commands.
+async fn get_candidates(configs: &ConfigChain<'_>, request: &GenerationRequest<'_>) -> Result<Vec<CommandResult>> {
+    let (text, provider) = generate(configs, request, None).await?;
+    let mut candidates = parse_candidates(&text);
+    candidates.truncate(request.candidates);
+    for candidate in &mut candidates {
+        candidate.provider = provider.clone();
+    }
+    Ok(candidates)
+}
+
+/// Ask each provider in turn, moving on only when one is down or rate limited.
+/// Returns the reply text and the label of the provider that answered.
+async fn generate(
+    configs: &ConfigChain<'_>,
+    request: &GenerationRequest<'_>,
+    mut on_token: TokenSink<'_>,
+) -> Result<(String, String)> {
+    let mut config = configs.primary();
+    let mut fallbacks = configs.fallbacks();
+
+    loop {
+        let sink: TokenSink<'_> = match on_token {
+            Some(ref mut on_token) => Some(&mut **on_token),
+            None => None,
+        };
+        let error = match get_command_from(config, request, sink).await {
+            Ok(text) => return Ok((text, config.label())),
+            Err(e) if should_fall_back(&e) => e,
+            Err(e) => return Err(e),
+        };
+        // Only now is the next profile worth resolving
+        let Some(next) = fallbacks.next() else {
+            return Err(error);
+        };
+        eprintln!(
+            "\r\x1b[K\x1b[33m⚠️  {} failed ({}), falling back to {}\x1b[0m",
+            config.name, error, next.name
+        );
+        config = next;
+    }
+}
+
+/// Whether another provider might succeed where this one failed.
+fn should_fall_back(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(api_error) = cause.downcast_ref::<ApiError>() {
+            return retry::is_retriable(api_error.status);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_connect() || e.is_timeout();
+        }
+        cause.is::<retry::TimedOut>()
+    })
+}
+
+async fn get_command_from(
+    config: &Config,
+    request: &GenerationRequest<'_>,
+    on_token: TokenSink<'_>,
+) -> Result<String> {
+    // Streaming can be switched off per profile for gateways that don't speak SSE
+    let on_token = on_token.filter(|_| config.stream);
+
+    match config.provider {
+        Provider::Gemini => get_command_gemini(config, request, on_token).await,
+        Provider::OpenAI | Provider::Azure => get_command_openai(config, request, on_token).await,
+        Provider::Anthropic => get_command_anthropic(config, request).await,
+        Provider::Ollama => get_command_ollama(config, request).await,
+    }
+}
+
+async fn get_command_gemini(
+    config: &Config,
+    request: &GenerationRequest<'_>,
+    on_token: TokenSink<'_>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+
+    let request_body = GeminiRequest {
+        contents: request
+            .messages()
+            .into_iter()
+            .map(|(role, text)| GeminiContent {
+                role: Some(
+                    match role {
+                        Role::User => "user",
+                        Role::Assistant => "model",
+                    }
+                    .to_string(),
+                ),
+                parts: vec![Part { text }],
+            })
+            .collect(),
+        system_instruction: GeminiContent {
+            role: None,
+            parts: vec![Part {
+                text: request.system_prompt.to_string(),
+            }],
+        },
+        generation_config: GenerationConfig {
+            max_output_tokens: request.max_tokens(config),
+            temperature: config.temperature,
+            response_mime_type: config.response_schema.then(|| "application/json".to_string()),
+            response_schema: config.response_schema.then(|| request.response_schema(SchemaDialect::Gemini)),
+        },
+    };
+
+    let url = match on_token {
+        Some(_) => format!(
+            "{}/models/{}:streamGenerateContent?alt=sse&key={}",
+            config.base_url, config.model, config.api_key
+        ),
+        None => format!(
+            "{}/models/{}:generateContent?key={}",
+            config.base_url, config.model, config.api_key
+        ),
+    };
+
+    let response = retry::send(&config.retry, client.post(&url).json(&request_body))
+        .await
+        .context("Failed to send request to Gemini API")?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        let response_text = response.text().await?;
+        return Err(ApiError::new("Gemini API", status, response_text).into());
+    }
+
+    if let Some(on_token) = on_token {
+        let mut text = String::new();
+        read_sse(response, |data| {
+            let chunk: GeminiResponse =
+                serde_json::from_str(data).context("Failed to parse Gemini stream chunk")?;
+            if let Some(error) = chunk.error {
+                anyhow::bail!("Gemini API error: {}", error.message);
+            }
+            for candidate in chunk.candidates.into_iter().flatten().take(1) {
+                for part in candidate.content.parts {
+                    on_token(&part.text);
+                    text.push_str(&part.text);
+                }
+            }
+            Ok(())
+        })
+        .await?;
+
+        if text.trim().is_empty() {
+            anyhow::bail!("No command generated from Gemini");
+        }
+        return Ok(text);
+    }
+
+    let response_text = response.text().await?;
+    let gemini_response: GeminiResponse =
+        serde_json::from_str(&response_text).context("Failed to parse Gemini response")?;
+
+    if let Some(error) = gemini_response.error {
+        anyhow::bail!("Gemini API error: {}", error.message);
+    }
+
+    let text = gemini_response
+        .candidates
+        .and_then(|c| c.into_iter().next())
+        .and_then(|c| c.content.parts.into_iter().next())
+        .map(|p| p.text)
+        .context("No command generated from Gemini")?;
+        
+    Ok(text)
+}
+
+async fn get_command_openai(
+    config: &Config,
+    request: &GenerationRequest<'_>,
+    on_token: TokenSink<'_>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+
+    let request_body = OpenAIRequest {
+        model: config.model.clone(),
+        messages: [Message {
+            role: "system".to_string(),
+            content: request.system_prompt.to_string(),
+        }]
+        .into_iter()
+        .chain(request.chat_messages())
+        .collect(),
+        max_tokens: request.max_tokens(config),
+        temperature: config.temperature,
+        stream: on_token.is_some(),
+        response_format: config.response_schema.then(|| {
+            serde_json::json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "shell_command",
+                    "strict": true,
+                    "schema": request.response_schema(SchemaDialect::JsonSchema),
+                },
+            })
+        }),
+    };
+
+    // Azure routes by deployment and authenticates with an `api-key` header
+    let http_request = match &config.azure {
+        Some(azure) => client
+            .post(format!(
+                "{}/openai/deployments/{}/chat/completions",
+                config.base_url, azure.deployment
+            ))
+            .query(&[("api-version", &azure.api_version)])
+            .header("api-key", &config.api_key),
+        None => client
+            .post(format!("{}/chat/completions", config.base_url))
+            .header("Authorization", format!("Bearer {}", config.api_key)),
+    };
+
+    let http_request = http_request
+        .header("Content-Type", "application/json")
+        .json(&request_body);
+
+    let response = retry::send(&config.retry, http_request)
+        .await
+        .context("Failed to send request to API")?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        let response_text = response.text().await?;
+        return Err(ApiError::new("API", status, response_text).into());
+    }
+
+    if let Some(on_token) = on_token {
+        let mut text = String::new();
+        read_sse(response, |data| {
+            let chunk: OpenAIStreamChunk =
+                serde_json::from_str(data).context("Failed to parse API stream chunk")?;
+            if let Some(error) = chunk.error {
+                anyhow::bail!("API error: {}", error.message);
+            }
+            // Azure sends an initial chunk with no choices (content filter results)
+            let delta = chunk
+                .choices
+                .and_then(|c| c.into_iter().next())
+                .and_then(|c| c.delta.content);
+            if let Some(delta) = delta {
+                on_token(&delta);
+                text.push_str(&delta);
+            }
+            Ok(())
+        })
+        .await?;
+
+        if text.trim().is_empty() {
+            anyhow::bail!("No command generated from API");
+        }
+        return Ok(text);
+    }
+
+    let response_text = response.text().await?;
+    let openai_response: OpenAIResponse =
+        serde_json::from_str(&response_text).context("Failed to parse API response")?;
+
+    if let Some(error) = openai_response.error {
+        anyhow::bail!("API error: {}", error.message);
+    }
+
+    let text = openai_response
+        .choices
+        .and_then(|c| c.into_iter().next())
+        .map(|c| c.message.content)
+        .context("No command generated from API")?;
+
+    Ok(text)
+}
+
+async fn get_command_anthropic(config: &Config, request: &GenerationRequest<'_>) -> Result<String> {
+    let client = reqwest::Client::new();
+
+    let request_body = AnthropicRequest {
+        model: config.model.clone(),
+        max_tokens: request.max_tokens(config),
+        system: request.system_prompt.to_string(),
+        messages: request.chat_messages(),
+        temperature: config.temperature,
+    };
+
+    let url = format!("{}/messages", config.base_url);
+
+    let http_request = client
+        .post(&url)
+        .header("x-api-key", &config.api_key)
+        .header("anthropic-version", ANTHROPIC_VERSION)
+        .json(&request_body);
+
+    let response = retry::send(&config.retry, http_request)
+        .await
+        .context("Failed to send request to Anthropic API")?;
+
+    let status = response.status();
+    let response_text = response.text().await?;
+
+    // Error bodies look like {"type":"error","error":{"type":"...","message":"..."}}
+    let anthropic_response = serde_json::from_str::<AnthropicResponse>(&response_text);
+
+    if !status.is_success() {
+        let message = match anthropic_response.ok().and_then(|r| r.error) {
+            Some(error) => error.message,
+            None => response_text,
+        };
+        return Err(ApiError::new("Anthropic API", status, message).into());
+    }
+
+    let anthropic_response = anthropic_response.context("Failed to parse Anthropic response")?;
+
+    if let Some(error) = anthropic_response.error {
+        anyhow::bail!("Anthropic API error: {}", error.message);
+    }
+
+    let text: String = anthropic_response
+        .content
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|block| block.kind == "text")
+        .filter_map(|block| block.text)
+        .collect();
+
+    if text.trim().is_empty() {
+        anyhow::bail!("No command generated from Anthropic");
+    }
+
+    Ok(text)
+}
+
+async fn get_command_ollama(config: &Config, request: &GenerationRequest<'_>) -> Result<String> {
+    let client = reqwest::Client::new();
+    let ollama = config.ollama.as_ref();
+
+    let request_body = OllamaRequest {
+        model: config.model.clone(),
+        messages: [Message {
+            role: "system".to_string(),
+            content: request.system_prompt.to_string(),
+        }]
+        .into_iter()
+        .chain(request.chat_messages())
+        .collect(),
+        stream: false,
+        keep_alive: ollama.and_then(|o| o.keep_alive.clone()),
+        options: OllamaOptions {
+            num_predict: request.max_tokens(config),
+            num_ctx: ollama.and_then(|o| o.num_ctx),
+            temperature: config.temperature,
+        },
+        format: config
+            .response_schema
+            .then(|| request.response_schema(SchemaDialect::JsonSchema)),
+    };
+
+    let url = format!("{}/api/chat", config.base_url);
+    let mut pulled = false;
+
+    loop {
+        let http_request = with_ollama_auth(config, client.post(&url)).json(&request_body);
+        let response = retry::send(&config.retry, http_request)
+            .await
+            .with_context(|| format!("Failed to reach Ollama at {}. Is `ollama serve` running?", config.base_url))?;
+
+        let status = response.status();
+        let response_text = response.text().await?;
+        let ollama_response = serde_json::from_str::<OllamaResponse>(&response_text);
+
+        // A missing model comes back as 404 {"error":"model \"x\" not found, try pulling it first"}
+        if status == reqwest::StatusCode::NOT_FOUND && !pulled {
+            ensure_ollama_model(&client, config).await?;
+            pulled = true;
+            continue;
+        }
+
+        if !status.is_success() {
+            let message = ollama_response.ok().and_then(|r| r.error).unwrap_or(response_text);
+            return Err(ApiError::new("Ollama", status, message).into());
+        }
+
+        let ollama_response = ollama_response.context("Failed to parse Ollama response")?;
+
+        if let Some(error) = ollama_response.error {
+            anyhow::bail!("Ollama error: {}", error);
+        }
+
+        let text = ollama_response
+            .message
+            .map(|m| m.content)
+            .context("No command generated from Ollama")?;
+
+        return Ok(text);
+    }
+}
+
+fn with_ollama_auth(config: &Config, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    // Plain Ollama has no auth, but it is often put behind a proxy that wants a token
+    if config.api_key.is_empty() {
+        request
+    } else {
+        request.header("Authorization", format!("Bearer {}", config.api_key))
+    }
+}
+
+async fn fetch_ollama_models(client: &reqwest::Client, config: &Config) -> Result<Vec<String>> {
+    let response = with_ollama_auth(config, client.get(format!("{}/api/tags", config.base_url)))
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach Ollama at {}. Is `ollama serve` running?", config.base_url))?;
+
+    let status = response.status();
+    let response_text = response.text().await?;
+
+    if !status.is_success() {
+        anyhow::bail!("Ollama error ({}): {}", status, response_text);
+    }
+
+    let tags: OllamaTags = serde_json::from_str(&response_text).context("Failed to parse Ollama model list")?;
+    Ok(tags.models.into_iter().map(|m| m.name).collect())
+}
+
+fn ollama_model_installed(models: &[String], model: &str) -> bool {
+    // `llama3.2` and `llama3.2:latest` name the same model
+    models
+        .iter()
+        .any(|m| m == model || (!model.contains(':') && *m == format!("{}:latest", model)))
+}
+
+/// Check the configured model against `/api/tags` and offer to pull it when missing.
+async fn ensure_ollama_model(client: &reqwest::Client, config: &Config) -> Result<()> {
+    let models = fetch_ollama_models(client, config).await?;
+    if ollama_model_installed(&models, &config.model) {
+        return Ok(());
+    }
+
+    let installed = if models.is_empty() {
+        "none".to_string()
+    } else {
+        models.join(", ")
+    };
+
+    // Only ask when someone can answer; the shell wrapper captures stderr
+    let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+    if !interactive {
+        anyhow::bail!(
+            "Ollama model '{}' is not installed (installed: {}). Run: ollama pull {}",
+            config.model,
+            installed,
+            config.model
+        );
+    }
+
+    eprint!(
+        "\x1b[33mOllama model '{}' is not installed (installed: {}). Pull it now? (y/N): \x1b[0m",
+        config.model, installed
+    );
+    io::stderr().flush().ok();
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    if !matches!(line.trim().to_lowercase().as_str(), "y" | "yes") {
+        anyhow::bail!("Ollama model '{}' is not installed", config.model);
+    }
+
+    pull_ollama_model(client, config).await
+}
+
+async fn pull_ollama_model(client: &reqwest::Client, config: &Config) -> Result<()> {
+    let mut response = with_ollama_auth(config, client.post(format!("{}/api/pull", config.base_url)))
+        .json(&serde_json::json!({ "model": config.model, "stream": true }))
+        .send()
+        .await
+        .context("Failed to send pull request to Ollama")?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let response_text = response.text().await?;
+        anyhow::bail!("Ollama pull failed ({}): {}", status, response_text);
+    }
+
+    // Progress arrives as newline-delimited JSON status objects
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=pos).collect();
+            let Ok(update) = serde_json::from_slice::<OllamaPullStatus>(&line) else {
+                continue;
+            };
+            if let Some(error) = update.error {
+                eprintln!();
+                anyhow::bail!("Ollama pull failed: {}", error);
+            }
+            let status = update.status.unwrap_or_default();
+            match (update.completed, update.total) {
+                (Some(completed), Some(total)) if total > 0 => {
+                    eprint!("\r\x1b[K\x1b[90m⬇️  {} {}%\x1b[0m", status, completed * 100 / total)
+                }
+                _ => eprint!("\r\x1b[K\x1b[90m⬇️  {}\x1b[0m", status),
+            }
+            io::stderr().flush().ok();
+        }
+    }
+
+    eprintln!("\r\x1b[K\x1b[32m✅ Pulled {}\x1b[0m", config.model);
+    Ok(())
+}
+
+async fn list_models(config: &Config) -> Result<()> {
+    if config.provider != Provider::Ollama {
+        anyhow::bail!("Model listing is only supported for the ollama provider");
+    }
+
+    let client = reqwest::Client::new();
+    for model in fetch_ollama_models(&client, config).await? {
+        let marker = if ollama_model_installed(std::slice::from_ref(&model), &config.model) { "*" } else { " " };
+        println!("{} {}", marker, model);
+    }
+    Ok(())
+}
+
+/// Strip markdown code blocks the model sometimes wraps the command in.
+fn clean_command(text: &str) -> String {
+    let text = text.trim();
+
+    if let Some(fenced) = text.strip_prefix("```") {
+        // Drop the opening fence with whatever language tag it has, and the closing one
+        let body = match fenced.split_once('\n') {
+            Some((_, body)) => body,
+            None => fenced,
+        };
+        return body.trim_end().trim_end_matches("```").trim().to_string();
+    }
+
+    // `command` in inline code
+    if let Some(inline) = text.strip_prefix('`').and_then(|t| t.strip_suffix('`'))
+        && !inline.contains('`')
+    {
+        return inline.trim().to_string();
+    }
+
+    text.to_string()
+}
+
+/// Renders the "💡 command" line while the response streams in.
+#[derive(Default)]
+struct CommandPreview {
+    text: String,
+    shown: String,
+    started: bool,
+}
 
-function wtf() {{
-    if [[ -z "$1" ]]; then
-        echo "Usage: wtf <natural language prompt>"
-        return 1
-    fi
+impl CommandPreview {
+    fn push(&mut self, token: &str) {
+        self.text.push_str(token);
 
-    echo -n "⏳ Generating..." >&2
+        // Wait until an opening code fence is complete so its language tag isn't shown
+        let text = self.text.trim_start();
+        if text.starts_with("```") && !text.contains('\n') {
+            return;
+        }
 
-    local cmd
-    cmd=$(command wtf --raw "$@" 2>&1)
-    local exit_code=$?
+        let body = text.trim_start_matches("```json").trim_start_matches("```").trim_start();
+        let visible = if body.starts_with('{') {
+            // Structured reply: show the "command" field as it fills in
+            match partial_json_string(body, "command") {
+                Some(command) => command.trim().to_string(),
+                None => return,
+            }
+        } else {
+            // Plain reply: hold back trailing characters that may still become a closing fence
+            clean_command(text)
+                .trim_end_matches(|c: char| c == '`' || c.is_whitespace())
+                .to_string()
+        };
+        let visible = visible.as_str();
 
-    echo -ne "\r\033[K" >&2
+        let Some(new_text) = visible.strip_prefix(self.shown.as_str()) else {
+            return;
+        };
+        if new_text.is_empty() {
+            return;
+        }
 
-    if [[ $exit_code -ne 0 ]]; then
-        echo "❌ $cmd"
-        return 1
-    fi
+        if !self.started {
+            print!("\r\x1b[K💡 \x1b[36m");
+            self.started = true;
+        }
+        print!("{}", new_text);
+        io::stdout().flush().ok();
+        self.shown = visible.to_string();
+    }
 
-    echo "💡 $cmd"
-    echo "📋 Copied to clipboard"
-    printf '%s' "$cmd" | pbcopy
-}}
+    /// Complete the line with the final cleaned command and its risk badge.
+    fn finish(&mut self, command: &str, badge: &str) {
+        if !self.started {
+            println!("\r\x1b[K💡 \x1b[36m{}\x1b[0m{}", command, badge);
+            return;
+        }
 
-alias '??'='wtf'
-"#);
+        match command.strip_prefix(self.shown.as_str()) {
+            Some(rest) => println!("{}\x1b[0m{}", rest, badge),
+            // What streamed doesn't match the final command, so show it again
+            None => println!("\x1b[0m\n💡 \x1b[36m{}\x1b[0m{}", command, badge),
         }
-        _ => {
-            eprintln!("Unsupported shell: {}. Supported: zsh, bash", shell);
-            std::process::exit(1);
+        self.started = false;
+    }
+
+    /// End a partially streamed line after an error.
+    fn abort(&mut self) {
+        if self.started {
+            println!("\x1b[0m");
+            self.started = false;
         }
     }
 }
 
-async fn get_command(config: &Config, prompt: &str, explain: bool) -> Result<CommandResult> {
-    match config.provider {
-        Provider::Gemini => get_command_gemini(config, prompt, explain).await,
-        Provider::OpenAI => get_command_openai(config, prompt, explain).await,
+/// Feed each `data:` payload of a server-sent event stream to `on_event`.
+async fn read_sse(mut response: retry::Reply, mut on_event: impl FnMut(&str) -> Result<()>) -> Result<()> {
+    let mut buffer = Vec::new();
+
+    while let Some(chunk) = response.chunk().await.context("Failed to read response stream")? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data.is_empty() || data == "[DONE]" {
+                continue;
+            }
+            on_event(data)?;
+        }
     }
-}
 
-async fn get_command_gemini(config: &Config, prompt: &str, explain: bool) -> Result<CommandResult> {
-    let client = reqwest::Client::new();
-    
-    let system_prompt = if explain { SYSTEM_PROMPT_EXPLAIN } else { SYSTEM_PROMPT };
+    Ok(())
+}
 
-    let request_body = GeminiRequest {
-        contents: vec![GeminiContent {
-            parts: vec![Part {
-                text: prompt.to_string(),
-            }],
-        }],
-        system_instruction: GeminiContent {
-            parts: vec![Part {
-                text: system_prompt.to_string(),
-            }],
-        },
-    };
+// ─────────────────────────────────────────────────────────────────────────────
+// Structured output
+// ─────────────────────────────────────────────────────────────────────────────
 
-    let url = format!(
-        "{}/models/{}:generateContent?key={}",
-        config.base_url, config.model, config.api_key
-    );
+/// The JSON object the model is asked to return. Everything but `command` is optional
+/// so replies from models without schema support still parse.
+#[derive(Deserialize)]
+struct StructuredOutput {
+    command: String,
+    #[serde(default)]
+    explanation: Option<String>,
+    #[serde(default)]
+    risk: Option<String>,
+    #[serde(default)]
+    requires: Vec<String>,
+    #[serde(default)]
+    placeholders: Vec<PlaceholderSpec>,
+    #[serde(default)]
+    note: Option<String>,
+}
 
-    let response = client
-        .post(&url)
-        .json(&request_body)
-        .send()
-        .await
-        .context("Failed to send request to Gemini API")?;
+/// A reply to a request for several alternatives.
+#[derive(Deserialize)]
+struct CandidatesOutput {
+    candidates: Vec<StructuredOutput>,
+}
 
-    let status = response.status();
-    let response_text = response.text().await?;
+/// A reply to `wtf explain '<command>'`.
+#[derive(Serialize, Deserialize)]
+struct Breakdown {
+    summary: String,
+    #[serde(default)]
+    segments: Vec<Segment>,
+}
 
-    if !status.is_success() {
-        anyhow::bail!("Gemini API error ({}): {}", status, response_text);
-    }
+/// One pipeline stage or chained command.
+#[derive(Serialize, Deserialize)]
+struct Segment {
+    text: String,
+    #[serde(default)]
+    program: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    options: Vec<OptionMeaning>,
+}
 
-    let gemini_response: GeminiResponse =
-        serde_json::from_str(&response_text).context("Failed to parse Gemini response")?;
+#[derive(Serialize, Deserialize)]
+struct OptionMeaning {
+    option: String,
+    meaning: String,
+}
 
-    if let Some(error) = gemini_response.error {
-        anyhow::bail!("Gemini API error: {}", error.message);
-    }
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PlaceholderSpec {
+    Described(Placeholder),
+    Bare(String),
+}
 
-    let text = gemini_response
-        .candidates
-        .and_then(|c| c.into_iter().next())
-        .and_then(|c| c.content.parts.into_iter().next())
-        .map(|p| p.text)
-        .context("No command generated from Gemini")?;
-        
-    Ok(parse_output(&text))
+enum SchemaDialect {
+    /// Gemini's OpenAPI subset: upper-case types, no `additionalProperties`
+    Gemini,
+    /// Standard JSON Schema as used by OpenAI strict mode and Ollama
+    JsonSchema,
 }
 
-async fn get_command_openai(config: &Config, prompt: &str, explain: bool) -> Result<CommandResult> {
-    let client = reqwest::Client::new();
+fn response_schema(dialect: SchemaDialect, candidates: usize) -> serde_json::Value {
+    let placeholder = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string" },
+            "description": { "type": "string" },
+        },
+        "required": ["name", "description"],
+        "additionalProperties": false,
+    });
 
-    let system_prompt = if explain { SYSTEM_PROMPT_EXPLAIN } else { SYSTEM_PROMPT };
+    let mut schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "command": { "type": "string" },
+            "explanation": { "type": "string" },
+            "risk": { "type": "string", "enum": ["low", "medium", "high"] },
+            "requires": { "type": "array", "items": { "type": "string" } },
+            "placeholders": { "type": "array", "items": placeholder },
+        },
+        "required": ["command", "explanation", "risk", "requires", "placeholders"],
+        "additionalProperties": false,
+    });
 
-    let request_body = OpenAIRequest {
-        model: config.model.clone(),
-        messages: vec![
-            Message {
-                role: "system".to_string(),
-                content: system_prompt.to_string(),
-            },
-            Message {
-                role: "user".to_string(),
-                content: prompt.to_string(),
+    if candidates > 1 {
+        schema["properties"]["note"] = serde_json::json!({ "type": "string" });
+        schema["required"].as_array_mut().unwrap().push("note".into());
+        schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "candidates": { "type": "array", "items": schema },
             },
-        ],
-        max_tokens: 500,
-    };
+            "required": ["candidates"],
+            "additionalProperties": false,
+        });
+    }
 
-    let url = format!("{}/chat/completions", config.base_url);
+    if let SchemaDialect::Gemini = dialect {
+        to_gemini_schema(&mut schema);
+    }
+    schema
+}
 
-    let response = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", config.api_key))
-        .header("Content-Type", "application/json")
-        .json(&request_body)
-        .send()
-        .await
-        .context("Failed to send request to API")?;
+fn breakdown_schema(dialect: SchemaDialect) -> serde_json::Value {
+    let option = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "option": { "type": "string" },
+            "meaning": { "type": "string" },
+        },
+        "required": ["option", "meaning"],
+        "additionalProperties": false,
+    });
+    let segment = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "text": { "type": "string" },
+            "program": { "type": "string" },
+            "description": { "type": "string" },
+            "options": { "type": "array", "items": option },
+        },
+        "required": ["text", "program", "description", "options"],
+        "additionalProperties": false,
+    });
+    let mut schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "summary": { "type": "string" },
+            "segments": { "type": "array", "items": segment },
+        },
+        "required": ["summary", "segments"],
+        "additionalProperties": false,
+    });
 
-    let status = response.status();
-    let response_text = response.text().await?;
+    if let SchemaDialect::Gemini = dialect {
+        to_gemini_schema(&mut schema);
+    }
+    schema
+}
 
-    if !status.is_success() {
-        anyhow::bail!("API error ({}): {}", status, response_text);
+fn to_gemini_schema(schema: &mut serde_json::Value) {
+    match schema {
+        serde_json::Value::Object(map) => {
+            map.remove("additionalProperties");
+            if let Some(serde_json::Value::String(kind)) = map.get_mut("type") {
+                *kind = kind.to_uppercase();
+            }
+            for value in map.values_mut() {
+                to_gemini_schema(value);
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(to_gemini_schema),
+        _ => {}
     }
+}
 
-    let openai_response: OpenAIResponse =
-        serde_json::from_str(&response_text).context("Failed to parse API response")?;
+fn parse_output(text: &str) -> CommandResult {
+    match parse_structured(text) {
+        Some(output) => to_command_result(output),
+        // The model ignored the JSON instructions: treat the whole reply as the command
+        None => CommandResult {
+            command: text.trim().to_string(),
+            ..Default::default()
+        },
+    }
+}
 
-    if let Some(error) = openai_response.error {
-        anyhow::bail!("API error: {}", error.message);
+/// Parse a reply to a request for several alternatives. Accepts `{"candidates": [...]}`,
+/// a bare array, a single object, or, from models ignoring the JSON instructions,
+/// one command per line.
+fn parse_candidates(text: &str) -> Vec<CommandResult> {
+    let body = strip_json_fence(text);
+
+    if let Ok(output) = serde_json::from_str::<CandidatesOutput>(body) {
+        return output.candidates.into_iter().map(to_command_result).collect();
+    }
+    if let Ok(outputs) = serde_json::from_str::<Vec<StructuredOutput>>(body) {
+        return outputs.into_iter().map(to_command_result).collect();
+    }
+    if let (Some(start), Some(end)) = (body.find('{'), body.rfind('}'))
+        && let Some(Ok(output)) = body.get(start..=end).map(serde_json::from_str::<CandidatesOutput>)
+    {
+        return output.candidates.into_iter().map(to_command_result).collect();
+    }
+    if let Some(output) = parse_structured(text) {
+        return vec![to_command_result(output)];
     }
 
-    let text = openai_response
-        .choices
-        .and_then(|c| c.into_iter().next())
-        .map(|c| c.message.content)
-        .context("No command generated from API")?;
+    clean_command(text)
+        .lines()
+        .map(|line| {
+            // Drop list markers such as "1." or "-"
+            let line = line.trim();
+            let line = line.trim_start_matches(|c: char| c.is_ascii_digit());
+            let line = line.strip_prefix(['.', ')']).unwrap_or(line);
+            line.trim_start_matches(['-', '*']).trim()
+        })
+        .filter(|line| !line.is_empty())
+        .map(|command| CommandResult {
+            command: command.to_string(),
+            ..Default::default()
+        })
+        .collect()
+}
 
-    Ok(parse_output(&text))
+fn to_command_result(output: StructuredOutput) -> CommandResult {
+    CommandResult {
+        command: output.command.trim().to_string(),
+        explanation: output.explanation.map(|e| e.trim().to_string()).filter(|e| !e.is_empty()),
+        risk: output.risk.as_deref().and_then(Risk::parse),
+        requires: output.requires,
+        placeholders: output
+            .placeholders
+            .into_iter()
+            .map(|p| match p {
+                PlaceholderSpec::Described(p) => p,
+                PlaceholderSpec::Bare(name) => Placeholder {
+                    name,
+                    description: String::new(),
+                },
+            })
+            .collect(),
+        note: output.note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty()),
+        ..Default::default()
+    }
 }
 
-fn parse_output(text: &str) -> CommandResult {
-    if let Some((cmd, expl)) = text.split_once("###") {
-        CommandResult {
-            command: cmd.trim().to_string(),
-            explanation: Some(expl.trim().to_string()),
-        }
-    } else {
-        CommandResult {
-            command: text.trim().to_string(),
-            explanation: None,
-        }
+fn strip_json_fence(text: &str) -> &str {
+    text.trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```")
+        .trim()
+}
+
+/// Parse the reply as JSON, tolerating code fences and chatter around the object.
+fn parse_structured(text: &str) -> Option<StructuredOutput> {
+    let text = strip_json_fence(text);
+
+    if let Ok(output) = serde_json::from_str(text) {
+        return Some(output);
     }
+
+    let start = text.find('{')?;
+    let end = text.rfind('}')?;
+    serde_json::from_str(text.get(start..=end)?).ok()
 }
 
+/// Decode the (possibly still incomplete) string value of `key` from a streaming JSON object.
+fn partial_json_string(text: &str, key: &str) -> Option<String> {
+    let key_start = text.find(&format!("\"{}\"", key))? + key.len() + 2;
+    let rest = text[key_start..].trim_start().strip_prefix(':')?.trim_start();
+    let mut chars = rest.strip_prefix('"')?.chars();
 
+    let mut value = String::new();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                        Some(decoded) => value.push(decoded),
+                        // Incomplete escape or a surrogate pair; stop here for now
+                        None => break,
+                    }
+                }
+                Some(other) => value.push(other),
+                None => break,
+            },
+            _ => value.push(ch),
+        }
+    }
+    Some(value)
+}
 
 // ─────────────────────────────────────────────────────────────────────────────
 // History
@@ -517,13 +2280,13 @@ fn strip_ansi_codes(text: &str) -> String {
     let mut chars = text.chars().peekable();
     
     while let Some(ch) = chars.next() {
-        if ch == '\x1b' || ch == '\u{001b}' {
+        if ch == '\x1b' {
             // Skip ANSI escape sequence
             if let Some('[') = chars.peek() {
                 chars.next(); // consume '['
                 // Skip until we find a letter (end of escape sequence)
                 while let Some(&next_ch) = chars.peek() {
-                    if next_ch.is_ascii_alphabetic() || next_ch == 'm' {
+                    if next_ch.is_ascii_alphabetic() {
                         chars.next();
                         break;
                     }
@@ -538,7 +2301,7 @@ fn strip_ansi_codes(text: &str) -> String {
     result
 }
 
-fn append_to_history(prompt: &str, command: &str) -> Result<()> {
+fn append_to_history(prompt: &str, command: &str, provider: Option<&str>) -> Result<()> {
     let path = get_history_path()?;
     
     // Strip any ANSI codes that might have accidentally gotten in
@@ -549,6 +2312,7 @@ fn append_to_history(prompt: &str, command: &str) -> Result<()> {
         timestamp: Utc::now().timestamp(),
         prompt: clean_prompt.trim().to_string(),
         command: clean_command.trim().to_string(),
+        provider: provider.map(str::to_string),
     };
     
     let json = serde_json::to_string(&entry)?;
@@ -620,7 +2384,7 @@ fn show_history() -> Result<()> {
         
         // Format timestamp
         let timestamp = chrono::DateTime::from_timestamp(entry.timestamp, 0)
-            .unwrap_or_else(|| chrono::Utc::now());
+            .unwrap_or_else(chrono::Utc::now);
         let time_str = timestamp.format("%Y-%m-%d %H:%M").to_string();
         
         // Truncate long commands for display
@@ -634,6 +2398,9 @@ fn show_history() -> Result<()> {
         println!("\x1b[90m[{:3}] {}\x1b[0m", num, time_str);
         println!("     \x1b[1mPrompt:\x1b[0m   {}", entry.prompt);
         println!("     \x1b[1mCommand:\x1b[0m  \x1b[36m{}\x1b[0m", command_display);
+        if let Some(provider) = &entry.provider {
+            println!("     \x1b[1mProvider:\x1b[0m \x1b[90m{}\x1b[0m", provider);
+        }
         
         // Show full command if truncated
         if entry.command.len() > 80 {
@@ -663,51 +2430,191 @@ fn clear_history() -> Result<()> {
 // Command Execution
 // ─────────────────────────────────────────────────────────────────────────────
 
-fn execute_command(command: &str) -> Result<()> {
+fn execute_command(session: &mut Session, command: &str) -> Result<Execution> {
     println!("\x1b[90m🚀 Executing...\x1b[0m");
     println!("\x1b[90m─────────────────────────────────────────────────────────\x1b[0m");
     
-    // Use shell to execute the command (supports pipes, redirects, etc.)
-    let output = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(["/C", command])
-            .output()
-            .context("Failed to execute command")?
-    } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .output()
-            .context("Failed to execute command")?
-    };
-    
-    // Print stdout
-    if !output.stdout.is_empty() {
-        print!("{}", String::from_utf8_lossy(&output.stdout));
-    }
-    
-    // Print stderr
-    if !output.stderr.is_empty() {
-        eprint!("{}", String::from_utf8_lossy(&output.stderr));
-    }
+    // Runs in the session's shell on a terminal, with output shown as it arrives
+    let execution = session.run(command)?;
     
     // Show exit status
     println!("\x1b[90m─────────────────────────────────────────────────────────\x1b[0m");
-    if output.status.success() {
-        println!("\x1b[32m✅ Command completed successfully\x1b[0m");
-    } else {
-        println!("\x1b[31m❌ Command failed with exit code: {}\x1b[0m", 
-                 output.status.code().unwrap_or(-1));
+    match &execution.signal {
+        _ if execution.success() => println!("\x1b[32m✅ Command completed successfully\x1b[0m"),
+        Some(signal) => println!("\x1b[31m❌ Command was stopped: {}\x1b[0m", signal),
+        None => println!("\x1b[31m❌ Command failed with exit code: {}\x1b[0m", execution.exit_code),
     }
     
-    Ok(())
+    Ok(execution)
+}
+
+/// Send a command that failed when run back to the model, with its exit code. The
+/// end of its output is in the conversation. `None` if no fix came back.
+async fn fix_failed_command(
+    configs: &ConfigChain<'_>,
+    checks: &Checks,
+    system_prompt: &str,
+    conversation: &mut Conversation,
+    command: &str,
+    execution: &Execution,
+) -> Option<String> {
+    let mut failed = fix::LastCommand {
+        command: command.to_string(),
+        exit_status: execution.exit_code as i32,
+        cwd: env::current_dir().unwrap_or_default(),
+        output: None,
+    };
+    // The conversation already has the end of the output
+    let brief = failed.prompt();
+    failed.output = Some(execution.output.clone());
+    let prompt = failed.prompt();
+
+    print!("\x1b[90m⏳ Asking for a fix...\x1b[0m\r");
+    io::stdout().flush().ok();
+
+    let generation = GenerationRequest {
+        history: Some(conversation),
+        ..GenerationRequest::new(system_prompt, &prompt)
+    };
+    match get_command(configs, &generation, None).await {
+        Ok(result) => {
+            // Clear loading indicator
+            print!("\r\x1b[K");
+            let result = review_command(configs, checks, &generation, result, None).await;
+            if result.command.is_empty() || result.command == command {
+                println!("\x1b[33m⚠️  No fix found for this command.\x1b[0m");
+                return None;
+            }
+            conversation.user(brief, "Asked for a fix");
+            conversation.assistant(reply_turn(&result), format!("Suggested: {}", result.command));
+            print_edited_command(&result.command);
+            Some(result.command)
+        }
+        Err(e) => {
+            // Clear loading indicator
+            print!("\r\x1b[K");
+            eprintln!("\x1b[33m⚠️  Failed to get a fix: {}\x1b[0m", e);
+            None
+        }
+    }
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
 // Interactive Mode
 // ─────────────────────────────────────────────────────────────────────────────
 
-async fn run_interactive_mode(config: &Config, explain: bool) -> Result<()> {
+fn print_edited_command(command: &str) {
+    let assessment = safety::assess(command);
+    println!("💡 \x1b[36m{}\x1b[0m{}", command, risk_badge(Some(assessment.risk)));
+    let color = if assessment.risk == Risk::High { "31" } else { "33" };
+    for reason in &assessment.reasons {
+        println!("\x1b[{}m⚠️  {}\x1b[0m", color, reason);
+    }
+}
+
+/// High-risk commands need the full word typed out rather than a quick 'y'.
+fn confirm_dangerous() -> Result<bool> {
+    print!("\x1b[1;31m⛔ This command is high risk. Type 'yes' to run it: \x1b[0m");
+    io::stdout().flush().ok();
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).context("Failed to read confirmation")?;
+    Ok(answer.trim() == "yes")
+}
+
+/// An answer as it goes into the conversation: in the format the model was asked for.
+fn reply_turn(result: &CommandResult) -> String {
+    serde_json::json!({
+        "command": result.command,
+        "explanation": result.explanation.as_deref().unwrap_or_default(),
+    })
+    .to_string()
+}
+
+/// A command the user typed in place of the model's answer.
+fn record_own_edit(conversation: &mut Conversation, command: &str) {
+    conversation.user(
+        format!("I changed the command to: {}", command),
+        format!("Changed it to: {}", command),
+    );
+}
+
+/// What running a command did, as the user tells the model. Only the last
+/// `REPORTED_OUTPUT` bytes of output are kept, so one noisy command doesn't push the
+/// rest of the session out of the history budget.
+fn execution_report(command: &str, execution: &Execution) -> String {
+    let mut report = format!("I ran {} and it {}.", command, execution_status(execution));
+    let output = execution.output.as_str();
+    let mut start = output.len().saturating_sub(REPORTED_OUTPUT);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    match &output[start..] {
+        "" => report.push_str(" It printed nothing."),
+        output => report.push_str(&format!(" The end of its output:\n{}", output)),
+    }
+    report
+}
+
+/// A request as the conversation keeps it: what the user typed, with the names of
+/// the files that went along but not their excerpts.
+fn request_turn(input: &str, attachments: &[attach::Attachment]) -> String {
+    if attachments.is_empty() {
+        return input.to_string();
+    }
+    let names: Vec<&str> = attachments.iter().map(|attachment| attachment.name.as_str()).collect();
+    format!("{}\n(Attached: {})", input, names.join(", "))
+}
+
+fn execution_status(execution: &Execution) -> String {
+    match &execution.signal {
+        _ if execution.success() => "succeeded".to_string(),
+        Some(signal) => format!("was stopped by {}", signal),
+        None => format!("failed with exit status {}", execution.exit_code),
+    }
+}
+
+/// Move wtf to the session shell's directory after a `cd`, so that relative @paths,
+/// the project config and what the model is told all follow it.
+fn follow_session(session: &Session, context: &mut PromptContext) -> Result<()> {
+    let dir = session.cwd();
+    context.cwd = Some(dir.to_path_buf());
+    env::set_current_dir(dir).with_context(|| format!("Cannot enter {}", dir.display()))?;
+    context.project = ProjectConfig::discover(dir)?;
+    if context.workspace.is_some() {
+        context.workspace = Some(Workspace::collect(dir));
+    }
+    Ok(())
+}
+
+/// `dir` with the home directory shortened to ~, for the prompt.
+fn display_dir(dir: &Path) -> String {
+    match env::var_os("HOME").and_then(|home| dir.strip_prefix(home).ok().map(Path::to_path_buf)) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => format!("~/{}", rest.display()),
+        None => dir.display().to_string(),
+    }
+}
+
+/// Offer to send a failed command back to the model, showing which attempt this is.
+fn ask_for_fix(attempt: u32, max: u32) -> Result<bool> {
+    print!("\x1b[90m🔧 Ask AI to fix it? ({}/{}) (y/N): \x1b[0m", attempt, max);
+    io::stdout().flush().ok();
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).context("Failed to read answer")?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+async fn run_interactive_mode(
+    configs: &ConfigChain<'_>,
+    checks: &Checks,
+    settings: &InteractiveSettings,
+    mut context: PromptContext,
+    explain: bool,
+    verbose: bool,
+    candidates: usize,
+) -> Result<()> {
     println!("\x1b[1;36m╔═══════════════════════════════════════════════════════════╗\x1b[0m");
     println!("\x1b[1;36m║  WTF Interactive Mode - Write The Formula 🚀            ║\x1b[0m");
     println!("\x1b[1;36m╚═══════════════════════════════════════════════════════════╝\x1b[0m");
@@ -725,17 +2632,27 @@ async fn run_interactive_mode(config: &Config, explain: bool) -> Result<()> {
         p.parent().map(|parent| parent.join(".wtf_interactive_history"))
     });
     
-    if let Some(ref path) = history_path {
-        if path.exists() {
-            let _ = rl.load_history(path);
-        }
+    if let Some(ref path) = history_path
+        && path.exists()
+    {
+        let _ = rl.load_history(path);
     }
 
-    // Conversation context for better AI responses
-    let mut conversation_context: Vec<String> = Vec::new();
+    // cd and export in one command carry over to the next
+    let mut session = Session::new(env::current_dir()?);
+    context.cwd = Some(session.cwd().to_path_buf());
+
+    let mut system_prompt = build_system_prompt(explain, candidates, &context);
+    // Repairs of a picked alternative ask for one command
+    let mut single_system_prompt = build_system_prompt(explain, 1, &context);
+    let mut edit_system_prompt = build_system_prompt(false, 1, &context);
+
+    // Sent with every request so follow-ups like "now only the big ones" make sense
+    let mut conversation = Conversation::new(settings.history_tokens as usize);
 
     loop {
-        match rl.readline("\x1b[1;36mwtf>\x1b[0m ") {
+        let prompt = format!("\x1b[1;36mwtf\x1b[0m \x1b[90m{}\x1b[0m\x1b[1;36m>\x1b[0m ", display_dir(session.cwd()));
+        match rl.readline(&prompt) {
             Ok(line) => {
                 let input = line.trim();
                 
@@ -777,66 +2694,123 @@ async fn run_interactive_mode(config: &Config, explain: bool) -> Result<()> {
                         // Add to readline history
                         let _ = rl.add_history_entry(input);
                         
+                        let (request_input, request_turn) = match attach::attach(input, &[], None) {
+                            Ok((request_input, attachments)) => {
+                                if verbose {
+                                    for attachment in &attachments {
+                                        println!("\x1b[90m📎 {}\x1b[0m", attachment.summary());
+                                    }
+                                }
+                                (request_input, request_turn(input, &attachments))
+                            }
+                            Err(e) => {
+                                eprintln!("\x1b[31m❌ Error: {:#}\x1b[0m", e);
+                                continue;
+                            }
+                        };
+
                         // Show loading indicator
                         print!("\x1b[90m⏳ Generating...\x1b[0m\r");
                         io::stdout().flush().ok();
                         
-                        // Build prompt with context if available
-                        let prompt_with_context = if conversation_context.is_empty() {
-                            input.to_string()
+                        // Get command from AI
+                        let mut preview = CommandPreview::default();
+                        let request = GenerationRequest {
+                            candidates,
+                            history: Some(&conversation),
+                            ..GenerationRequest::new(&system_prompt, &request_input)
+                        };
+                        let result = if candidates > 1 {
+                            match get_candidates(configs, &request).await {
+                                Ok(choices) => pick_candidate(choices)
+                                    .and_then(|choice| choice.context("No command selected")),
+                                Err(e) => Err(e),
+                            }
                         } else {
-                            let context = conversation_context.join("\n");
-                            format!("Previous conversation:\n{}\n\nNew request: {}", context, input)
+                            get_command(configs, &request, Some(&mut |token: &str| preview.push(token))).await
                         };
-                        
-                        // Get command from AI
-                        match get_command(config, &prompt_with_context, explain).await {
+
+                        match result {
                             Ok(result) => {
-                                // Clear loading indicator
-                                print!("\r\x1b[K");
-                                
-                                // Strip markdown code blocks if present
-                                let command = result.command
-                                    .trim()
-                                    .trim_start_matches("```bash")
-                                    .trim_start_matches("```sh")
-                                    .trim_start_matches("```")
-                                    .trim_end_matches("```")
-                                    .trim()
-                                    .to_string();
+                                let review = GenerationRequest {
+                                    history: Some(&conversation),
+                                    ..GenerationRequest::new(&single_system_prompt, &request_input)
+                                };
+                                let mut result = review_command(configs, checks, &review, result, Some(&mut preview)).await;
+                                result.check_safety();
+                                let command = result.command.clone();
+                                conversation.user(request_turn, format!("Asked: {}", input));
+                                conversation.assistant(reply_turn(&result), format!("Suggested: {}", command));
                                 
                                 // Save to history
-                                if let Err(e) = append_to_history(input, &command) {
+                                if let Err(e) = append_to_history(input, &command, Some(&result.provider)) {
                                     eprintln!("\x1b[33mWarning: Failed to save history: {}\x1b[0m", e);
                                 }
                                 
-                                // Display result
-                                println!("💡 \x1b[36m{}\x1b[0m", command);
-                                
-                                if let Some(explanation) = result.explanation {
-                                    println!("\x1b[90m📝 {}\x1b[0m", explanation.trim());
-                                }
+                                // Display result (clears the loading indicator if nothing streamed)
+                                preview.finish(&command, risk_badge(result.risk));
+                                print_command_details(&result, explain, verbose);
                                 
                                 // Ask if user wants to run the command
                                 let mut final_command = command;
+                                let mut fix_attempts = 0;
                                 loop {
                                     print!("\x1b[90mRun this command? (y/n/e to edit): \x1b[0m");
                                     io::stdout().flush().ok();
                                     
-                                    let stdin = io::stdin();
                                     let mut line = String::new();
+                                    // Release the stdin lock before the arms read from it again
+                                    let read = io::stdin().lock().read_line(&mut line);
                                     
-                                    match stdin.lock().read_line(&mut line) {
+                                    match read {
                                         Ok(_) => {
                                             let choice = line.trim().to_lowercase();
                                             match choice.as_str() {
                                                 "y" | "yes" => {
+                                                    if safety::assess(&final_command).risk == Risk::High
+                                                        && !confirm_dangerous()?
+                                                    {
+                                                        println!("\x1b[90mSkipped.\x1b[0m");
+                                                        conversation.user("I didn't run it.", "Didn't run it");
+                                                        break;
+                                                    }
                                                     // Execute the command
-                                                    execute_command(&final_command)?;
-                                                    break;
+                                                    let execution = execute_command(&mut session, &final_command)?;
+                                                    conversation.user(
+                                                        execution_report(&final_command, &execution),
+                                                        format!("Ran {}: {}", final_command, execution_status(&execution)),
+                                                    );
+                                                    let moved = context.cwd.as_deref() != Some(session.cwd());
+                                                    if moved || context.variables != session.variables() {
+                                                        if moved && let Err(e) = follow_session(&session, &mut context) {
+                                                            eprintln!("\x1b[33m⚠️  {:#}\x1b[0m", e);
+                                                        }
+                                                        context.variables = session.variables().to_vec();
+                                                        system_prompt = build_system_prompt(explain, candidates, &context);
+                                                        single_system_prompt = build_system_prompt(explain, 1, &context);
+                                                        edit_system_prompt = build_system_prompt(false, 1, &context);
+                                                    }
+                                                    // Ctrl+C is not a failure to fix; a crash or being killed can be
+                                                    if execution.success()
+                                                        || execution.stopped_by_user()
+                                                        || fix_attempts >= settings.fix_attempts
+                                                        || !ask_for_fix(fix_attempts + 1, settings.fix_attempts)?
+                                                    {
+                                                        break;
+                                                    }
+                                                    fix_attempts += 1;
+                                                    match fix_failed_command(configs, checks, &edit_system_prompt, &mut conversation, &final_command, &execution).await {
+                                                        // Loop back to ask again
+                                                        Some(fixed) => {
+                                                            final_command = fixed;
+                                                            continue;
+                                                        }
+                                                        None => break,
+                                                    }
                                                 }
                                                 "n" | "no" | "" => {
                                                     println!("\x1b[90mSkipped.\x1b[0m");
+                                                    conversation.user("I didn't run it.", "Didn't run it");
                                                     break;
                                                 }
                                                 "e" | "edit" => {
@@ -850,53 +2824,45 @@ async fn run_interactive_mode(config: &Config, explain: bool) -> Result<()> {
                                                                 continue;
                                                             }
                                                             
-                                                            // Check if it looks like a direct command (starts with common commands, has pipes, etc.)
-                                                            let looks_like_command = edit_request.contains('|') 
-                                                                || edit_request.contains("&&")
-                                                                || edit_request.contains(';')
-                                                                || edit_request.starts_with("find")
-                                                                || edit_request.starts_with("grep")
-                                                                || edit_request.starts_with("ls")
-                                                                || edit_request.starts_with("cat")
-                                                                || edit_request.starts_with("curl")
-                                                                || edit_request.starts_with("git")
-                                                                || edit_request.starts_with("docker")
-                                                                || edit_request.starts_with("kubectl");
-                                                            
-                                                            if looks_like_command {
+                                                            // A direct command parses, runs a program that exists and uses shell syntax
+                                                            if shell::looks_like_command(edit_request) {
                                                                 // User provided a direct command, use it as-is
                                                                 final_command = edit_request.to_string();
-                                                                println!("💡 \x1b[36m{}\x1b[0m", final_command);
+                                                                print_edited_command(&final_command);
+                                                                record_own_edit(&mut conversation, &final_command);
                                                             } else {
                                                                 // Natural language edit - use AI to modify the command
                                                                 print!("\x1b[90m⏳ Applying edit...\x1b[0m\r");
                                                                 io::stdout().flush().ok();
                                                                 
                                                                 let edit_prompt = format!(
-                                                                    "Current command: {}\n\nUser wants to modify it: {}\n\nGenerate the modified command. Output ONLY the new command, nothing else.",
+                                                                    "Current command: {}\n\nUser wants to modify it: {}\n\nGenerate the modified command.",
                                                                     final_command, edit_request
                                                                 );
                                                                 
-                                                                match get_command(config, &edit_prompt, false).await {
+                                                                let generation = GenerationRequest {
+                                                                    history: Some(&conversation),
+                                                                    ..GenerationRequest::new(&edit_system_prompt, &edit_prompt)
+                                                                };
+                                                                match get_command(configs, &generation, None).await {
                                                                     Ok(edited_result) => {
                                                                         // Clear loading indicator
                                                                         print!("\r\x1b[K");
                                                                         
-                                                                        let new_command = edited_result.command
-                                                                            .trim()
-                                                                            .trim_start_matches("```bash")
-                                                                            .trim_start_matches("```sh")
-                                                                            .trim_start_matches("```")
-                                                                            .trim_end_matches("```")
-                                                                            .trim()
-                                                                            .to_string();
+                                                                        let edited_result = review_command(configs, checks, &generation, edited_result, None).await;
                                                                         
-                                                                        if !new_command.is_empty() {
-                                                                            final_command = new_command;
-                                                                            println!("💡 \x1b[36m{}\x1b[0m", final_command);
+                                                                        if !edited_result.command.is_empty() {
+                                                                            conversation.user(edit_prompt, format!("Asked to change it: {}", edit_request));
+                                                                            conversation.assistant(
+                                                                                reply_turn(&edited_result),
+                                                                                format!("Suggested: {}", edited_result.command),
+                                                                            );
+                                                                            final_command = edited_result.command;
+                                                                            print_edited_command(&final_command);
                                                                         } else {
                                                                             println!("\x1b[33m⚠️  Could not generate modified command. Using your input as-is.\x1b[0m");
                                                                             final_command = edit_request.to_string();
+                                                                            record_own_edit(&mut conversation, &final_command);
                                                                         }
                                                                     }
                                                                     Err(e) => {
@@ -905,6 +2871,7 @@ async fn run_interactive_mode(config: &Config, explain: bool) -> Result<()> {
                                                                         eprintln!("\x1b[33m⚠️  Failed to process edit with AI: {}\x1b[0m", e);
                                                                         println!("\x1b[90mUsing your input as direct command.\x1b[0m");
                                                                         final_command = edit_request.to_string();
+                                                                        record_own_edit(&mut conversation, &final_command);
                                                                     }
                                                                 }
                                                             }
@@ -935,16 +2902,11 @@ async fn run_interactive_mode(config: &Config, explain: bool) -> Result<()> {
                                     }
                                 }
                                 
-                                // Add to conversation context (keep last 3 interactions)
-                                conversation_context.push(format!("User: {}\nAssistant: {}", input, final_command));
-                                if conversation_context.len() > 3 {
-                                    conversation_context.remove(0);
-                                }
-                                
                                 println!();
                             }
                             Err(e) => {
-                                // Clear loading indicator
+                                // Clear loading indicator or end a partially streamed line
+                                preview.abort();
                                 print!("\r\x1b[K");
                                 eprintln!("\x1b[31m❌ Error: {}\x1b[0m", e);
                                 println!();
@@ -975,3 +2937,89 @@ async fn run_interactive_mode(config: &Config, explain: bool) -> Result<()> {
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    /// Answers one request with `reply` as the JSON body and hands back the request's
+    /// head (request line and headers) and body.
+    fn serve_once(reply: &'static str) -> (String, std::thread::JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let length = head
+                .to_ascii_lowercase()
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:")?.trim().parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reply.len(),
+                reply
+            )
+            .unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        (base_url, server)
+    }
+
+    #[tokio::test]
+    async fn azure_requests_go_to_the_deployment() {
+        let (base_url, server) = serve_once(r#"{"choices":[{"message":{"content":"ls -la"}}]}"#);
+        let config = Config {
+            name: "azure".to_string(),
+            api_key: "secret".to_string(),
+            base_url,
+            model: "gpt-4o-mini".to_string(),
+            provider: Provider::Azure,
+            temperature: None,
+            max_tokens: 100,
+            stream: false,
+            response_schema: false,
+            retry: retry::RetryPolicy {
+                max_retries: 0,
+                timeout: Duration::from_secs(10),
+            },
+            azure: Some(config::AzureConfig {
+                deployment: "my-deployment".to_string(),
+                api_version: "2024-06-01".to_string(),
+            }),
+            ollama: None,
+        };
+
+        let request = GenerationRequest::new("system", "list files");
+        let text = get_command_openai(&config, &request, None).await.unwrap();
+        assert_eq!(text, "ls -la");
+
+        let (head, body) = server.join().unwrap();
+        let mut lines = head.lines();
+        assert_eq!(
+            lines.next(),
+            Some("POST /openai/deployments/my-deployment/chat/completions?api-version=2024-06-01 HTTP/1.1")
+        );
+        let headers: Vec<String> = lines.map(str::to_ascii_lowercase).collect();
+        assert!(headers.contains(&"api-key: secret".to_string()));
+        assert!(!headers.iter().any(|header| header.starts_with("authorization:")));
+        assert!(body.contains("list files"));
+    }
+}
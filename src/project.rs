@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PROJECT_FILE: &str = ".wtf.toml";
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Project-local prompt settings (.wtf.toml)
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Example:
+//
+//   instructions = """
+//   Tasks are run with `just`, never call npm directly.
+//   Services run under docker compose.
+//   """
+//   preferred_tools = ["just", "docker compose", "rg"]
+//
+//   [[examples]]
+//   prompt = "run the tests"
+//   command = "just test"
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    #[serde(skip)]
+    pub path: PathBuf,
+    pub instructions: Option<String>,
+    #[serde(default)]
+    pub preferred_tools: Vec<String>,
+    #[serde(default)]
+    pub examples: Vec<Example>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Example {
+    pub prompt: String,
+    pub command: String,
+}
+
+impl ProjectConfig {
+    /// Walk up from `start` and load the nearest `.wtf.toml`, if any.
+    pub fn discover(start: &Path) -> Result<Option<Self>> {
+        for dir in start.ancestors() {
+            let path = dir.join(PROJECT_FILE);
+            if path.is_file() {
+                return Self::load(&path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut project: ProjectConfig =
+            toml::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?;
+        project.path = path.to_path_buf();
+        Ok(project)
+    }
+
+    /// Render the project section appended to the system prompt.
+    pub fn prompt_section(&self) -> String {
+        let mut section = format!("\nProject conventions (from {}):\n", self.path.display());
+
+        if let Some(instructions) = &self.instructions {
+            section.push_str(instructions.trim());
+            section.push('\n');
+        }
+
+        if !self.preferred_tools.is_empty() {
+            section.push_str(&format!(
+                "Prefer these tools over alternatives when they fit the task: {}\n",
+                self.preferred_tools.join(", ")
+            ));
+        }
+
+        if !self.examples.is_empty() {
+            section.push_str("\nProject examples:\n");
+            for example in &self.examples {
+                section.push_str(&format!(
+                    "User: {}\nCommand: {}\n\n",
+                    example.prompt.trim(),
+                    example.command.trim()
+                ));
+            }
+        }
+
+        section
+    }
+}
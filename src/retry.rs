@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(8);
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Retry with backoff for rate limits and server errors
+// ─────────────────────────────────────────────────────────────────────────────
+
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    /// Cap on total wall time across all attempts and waits
+    pub timeout: Duration,
+}
+
+/// The total time budget ran out before a response arrived.
+#[derive(Debug)]
+pub struct TimedOut(pub Duration);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request timed out after {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// A response, with whatever is left of the policy's timeout to read its body in. A
+/// body or stream that stalls would otherwise hang past the timeout, and keep the
+/// fallback chain from moving on.
+pub struct Reply {
+    response: Response,
+    deadline: Instant,
+    timeout: Duration,
+}
+
+impl Reply {
+    pub fn status(&self) -> StatusCode {
+        self.response.status()
+    }
+
+    pub async fn text(self) -> Result<String> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(remaining, self.response.text()).await {
+            Ok(text) => Ok(text?),
+            Err(_) => Err(TimedOut(self.timeout).into()),
+        }
+    }
+
+    /// The next piece of a streamed body.
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(remaining, self.response.chunk()).await {
+            Ok(chunk) => Ok(chunk?.map(|bytes| bytes.to_vec())),
+            Err(_) => Err(TimedOut(self.timeout).into()),
+        }
+    }
+}
+
+/// Send `request`, retrying 429/5xx responses and timeouts with jittered exponential
+/// backoff. `Retry-After` is honored when the server sends it. Gives up early rather
+/// than exceed the policy's total timeout; the last response is returned as-is so the
+/// caller can report it.
+pub async fn send(policy: &RetryPolicy, request: RequestBuilder) -> Result<Reply> {
+    let started = Instant::now();
+    let reply = |response| Reply {
+        response,
+        deadline: started + policy.timeout,
+        timeout: policy.timeout,
+    };
+    let mut indicator = RetryIndicator::default();
+    let mut attempt = 0;
+
+    loop {
+        let this_try = request.try_clone().context("Request cannot be retried")?;
+        let remaining = policy.timeout.saturating_sub(started.elapsed());
+
+        let Ok(outcome) = tokio::time::timeout(remaining, this_try.send()).await else {
+            indicator.clear();
+            return Err(TimedOut(policy.timeout).into());
+        };
+
+        let (reason, retry_after) = match &outcome {
+            Ok(response) if is_retriable(response.status()) => {
+                (response.status().to_string(), retry_after(response.headers()))
+            }
+            Err(e) if e.is_timeout() => ("request timed out".to_string(), None),
+            _ => {
+                indicator.clear();
+                return Ok(reply(outcome?));
+            }
+        };
+
+        let delay = retry_after.unwrap_or_else(|| backoff(attempt));
+        if attempt >= policy.max_retries || started.elapsed() + delay > policy.timeout {
+            indicator.clear();
+            return Ok(reply(outcome?));
+        }
+
+        attempt += 1;
+        indicator.show(&reason, attempt, policy.max_retries, delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+pub fn is_retriable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED
+}
+
+/// Parse `Retry-After` as either delay-seconds or an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<f64>() {
+        return (seconds >= 0.0).then(|| Duration::from_secs_f64(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.signed_duration_since(chrono::Utc::now()).to_std().unwrap_or_default();
+    Some(wait)
+}
+
+/// Exponential backoff with jitter: a random delay in [d/2, d] where d doubles each attempt.
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_DELAY);
+    delay.mul_f64(0.5 + jitter() * 0.5)
+}
+
+/// A random number in [0, 1) without pulling in a RNG crate.
+fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(Instant::now().elapsed().as_nanos());
+    (hasher.finish() % 10_000) as f64 / 10_000.0
+}
+
+/// "Retrying (2/3)…" status line on stderr, overwritten in place.
+#[derive(Default)]
+struct RetryIndicator {
+    shown: bool,
+}
+
+impl RetryIndicator {
+    fn show(&mut self, reason: &str, attempt: u32, max_retries: u32, delay: Duration) {
+        eprint!(
+            "\r\x1b[K\x1b[90m⏳ {} - retrying ({}/{}) in {:.1}s…\x1b[0m",
+            reason,
+            attempt,
+            max_retries,
+            delay.as_secs_f64()
+        );
+        io::stderr().flush().ok();
+        self.shown = true;
+    }
+
+    fn clear(&mut self) {
+        if self.shown {
+            eprint!("\r\x1b[K");
+            io::stderr().flush().ok();
+            self.shown = false;
+        }
+    }
+}
@@ -0,0 +1,1496 @@
+use anyhow::{bail, Result};
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::{Command as Process, Stdio};
+
+// ─────────────────────────────────────────────────────────────────────────────
+// POSIX/bash command parser
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Turns a generated command line into a small syntax tree: lists of pipelines of
+// simple or compound commands, with words split into literal text, parameter
+// expansions and command substitutions. It covers the syntax models actually
+// produce (quoting, redirections, heredocs, subshells, if/for/while/case, functions)
+// and is meant for inspecting commands, not for running them. A tree prints back as
+// shell source (its `Display`) that parses to the same tree.
+
+/// A sequence of and-or lists separated by `;`, `&` or newlines.
+#[derive(Debug, PartialEq)]
+pub struct Script {
+    pub items: Vec<AndOr>,
+}
+
+/// Pipelines joined by `&&` and `||`.
+#[derive(Debug, PartialEq)]
+pub struct AndOr {
+    pub pipelines: Vec<Pipeline>,
+    /// `&&` or `||` before each pipeline after the first
+    pub operators: Vec<&'static str>,
+    /// Ended by `&` rather than `;` or a newline
+    pub background: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+    /// `|` or `|&` before each command after the first
+    pub operators: Vec<&'static str>,
+    /// Started with `!`
+    pub negated: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// Subshells, brace groups and if/while/until/for/case: their bodies, plus the
+    /// words they expand (a for loop's list, a case subject and patterns)
+    Compound {
+        kind: CompoundKind,
+        bodies: Vec<Script>,
+        words: Vec<Word>,
+        redirects: Vec<Redirect>,
+    },
+    Function {
+        name: String,
+        body: Box<Command>,
+    },
+}
+
+/// What a compound command is, and what it takes to lay its bodies and words out again.
+#[derive(Debug, PartialEq)]
+pub enum CompoundKind {
+    Subshell,
+    Group,
+    /// Bodies are condition, branch, then more condition and branch pairs for `elif`,
+    /// and the `else` branch last
+    If,
+    While,
+    Until,
+    /// Without an `in` list the loop runs over "$@"
+    For { variable: String, listed: bool },
+    /// How many of the words after the subject are patterns for each body
+    Case { patterns: Vec<usize> },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SimpleCommand {
+    /// Leading `NAME=value` words
+    pub assignments: Vec<Word>,
+    /// The program and its arguments
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Redirect {
+    /// The file descriptor written before the operator, as in `2>&1`
+    pub fd: Option<u32>,
+    /// The operator without its file descriptor, e.g. `>>` or `<<-`
+    pub op: &'static str,
+    /// The file, descriptor or heredoc delimiter
+    pub target: Word,
+    /// Body of a `<<` heredoc
+    pub heredoc: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WordPart {
+    /// Text with quoting and escapes already removed, and whether it was quoted or
+    /// escaped (which keeps globs, `~` and reserved words from meaning anything)
+    Literal { text: String, quoted: bool },
+    /// `$name`, `${…}` or a special parameter, as written, and whether it is inside double quotes
+    Param { text: String, quoted: bool },
+    /// `$(…)`, backticks or process substitution: the parsed body, the source text, and
+    /// whether it is inside double quotes
+    Command { script: Script, source: String, quoted: bool },
+    /// `$((…))` as written
+    Arithmetic(String),
+}
+
+impl Script {
+    /// Every pipeline, including those inside compound commands, function bodies and
+    /// command substitutions.
+    pub fn pipelines(&self) -> Vec<&Pipeline> {
+        let mut pipelines = Vec::new();
+        self.collect_pipelines(&mut pipelines);
+        pipelines
+    }
+
+    fn collect_pipelines<'a>(&'a self, out: &mut Vec<&'a Pipeline>) {
+        for pipeline in self.items.iter().flat_map(|item| &item.pipelines) {
+            out.push(pipeline);
+            for command in &pipeline.commands {
+                command.collect_pipelines(out);
+            }
+        }
+    }
+
+    /// Simple commands in execution order, nested ones included.
+    pub fn simple_commands(&self) -> Vec<&SimpleCommand> {
+        self.pipelines()
+            .into_iter()
+            .flat_map(|pipeline| &pipeline.commands)
+            .filter_map(|command| match command {
+                Command::Simple(simple) => Some(simple),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Command {
+    fn collect_pipelines<'a>(&'a self, out: &mut Vec<&'a Pipeline>) {
+        match self {
+            Command::Simple(simple) => {
+                let words = simple.assignments.iter().chain(&simple.words);
+                for word in words.chain(simple.redirects.iter().map(|r| &r.target)) {
+                    word.collect_pipelines(out);
+                }
+            }
+            Command::Compound {
+                bodies,
+                words,
+                redirects,
+                ..
+            } => {
+                for body in bodies {
+                    body.collect_pipelines(out);
+                }
+                for word in words.iter().chain(redirects.iter().map(|r| &r.target)) {
+                    word.collect_pipelines(out);
+                }
+            }
+            Command::Function { body, .. } => body.collect_pipelines(out),
+        }
+    }
+
+    pub fn redirects(&self) -> &[Redirect] {
+        match self {
+            Command::Simple(simple) => &simple.redirects,
+            Command::Compound { redirects, .. } => redirects,
+            Command::Function { .. } => &[],
+        }
+    }
+}
+
+impl SimpleCommand {
+    /// The words as the shell would pass them, with quotes removed and expansions left as written.
+    pub fn argv(&self) -> Vec<String> {
+        self.words.iter().map(Word::value).collect()
+    }
+
+    /// The program name, if it is spelled out rather than computed by an expansion.
+    pub fn program(&self) -> Option<String> {
+        self.words.first().filter(|word| word.is_literal()).map(Word::value)
+    }
+}
+
+impl Redirect {
+    /// Whether this creates or overwrites the target file.
+    pub fn writes_file(&self) -> bool {
+        matches!(self.op, ">" | ">>" | ">|" | "&>" | "&>>" | "<>")
+    }
+}
+
+impl Word {
+    pub fn value(&self) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                WordPart::Literal { text, .. } | WordPart::Param { text, .. } | WordPart::Arithmetic(text) => {
+                    text.as_str()
+                }
+                WordPart::Command { source, .. } => source.as_str(),
+            })
+            .collect()
+    }
+
+    pub fn is_literal(&self) -> bool {
+        self.parts.iter().all(|part| matches!(part, WordPart::Literal { .. }))
+    }
+
+    fn collect_pipelines<'a>(&'a self, out: &mut Vec<&'a Pipeline>) {
+        for part in &self.parts {
+            if let WordPart::Command { script, .. } = part {
+                script.collect_pipelines(out);
+            }
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Script> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        pos: 0,
+        peeked: None,
+        heredoc_resume: None,
+        fd: None,
+    };
+    let script = parser.script(&[])?;
+    match parser.next()? {
+        Token::Eof => Ok(script),
+        token => bail!("syntax error near unexpected token `{}`", token),
+    }
+}
+
+/// Builtins and keywords that never show up on PATH.
+pub const BUILTINS: &[&str] = &[
+    ".", ":", "[", "[[", "alias", "bg", "bind", "break", "builtin", "caller", "cd", "command", "compgen",
+    "complete", "continue", "declare", "dirs", "disown", "echo", "enable", "eval", "exec", "exit", "export",
+    "false", "fc", "fg", "getopts", "hash", "help", "history", "jobs", "kill", "let", "local", "logout",
+    "popd", "printf", "pushd", "pwd", "read", "readonly", "return", "set", "shift", "shopt", "source",
+    "suspend", "test", "times", "trap", "true", "type", "typeset", "ulimit", "umask", "unalias", "unset",
+    "wait", "setopt", "unsetopt", "autoload", "print", "whence", "where", "which", "functions", "noglob",
+];
+
+/// Resolve `program` the way the shell would, without running anything.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &std::path::Path) -> bool {
+    path.is_file()
+}
+
+/// Programs that run the command given in their arguments, with the options that take a value.
+const WRAPPERS: &[(&str, &[&str])] = &[
+    ("sudo", &["-u", "-g", "-C", "-h", "-p"]),
+    ("doas", &["-u", "-C"]),
+    ("env", &["-u", "-C", "-S"]),
+    ("nice", &["-n"]),
+    ("nohup", &[]),
+    ("time", &[]),
+    ("exec", &["-a"]),
+    ("command", &[]),
+    ("xargs", &["-I", "-n", "-P", "-L", "-d", "-E", "-s", "-a"]),
+    ("watch", &["-n"]),
+    ("timeout", &["-s", "-k"]),
+];
+
+/// The command that `program` runs when it is a wrapper such as `sudo`, `env FOO=bar`
+/// or `xargs`: `args` with the wrapper's own options skipped. `None` for other programs.
+pub fn wrapped_command<'a>(program: &str, args: &'a [String]) -> Option<&'a [String]> {
+    let name = basename(program);
+    let (_, takes_value) = WRAPPERS.iter().find(|(wrapper, _)| *wrapper == name)?;
+
+    let mut args = args;
+    while let Some((arg, rest)) = args.split_first() {
+        if takes_value.contains(&arg.as_str()) {
+            args = rest.get(1..).unwrap_or_default();
+        } else if arg.starts_with('-') || (name == "env" && arg.contains('=')) {
+            args = rest;
+        } else {
+            break;
+        }
+    }
+    if name == "timeout" {
+        // The duration
+        args = args.get(1..).unwrap_or_default();
+    }
+    Some(args)
+}
+
+/// `rm` for `/bin/rm`.
+pub fn basename(program: &str) -> &str {
+    program.rsplit('/').next().unwrap_or(program)
+}
+
+/// The shell generated commands end up in: the user's login shell when it is one we
+/// know how to syntax check, otherwise sh.
+pub fn target_shell() -> String {
+    env::var("SHELL")
+        .ok()
+        .and_then(|path| path.rsplit('/').next().map(str::to_string))
+        .filter(|name| matches!(name.as_str(), "bash" | "zsh" | "sh" | "dash" | "ksh"))
+        .unwrap_or_else(|| "sh".to_string())
+}
+
+/// Check `command` for syntax errors without running it, with the target shell's `-n`
+/// mode, or with our own parser if that shell can't be started.
+pub fn check_syntax(command: &str) -> Result<(), String> {
+    let shell = target_shell();
+    let output = Process::new(&shell)
+        .args(["-n", "-c", command])
+        .stdin(Stdio::null())
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // "bash: -c: line 1: …" reads better without the prefix
+            let message = stderr.trim().replace(&format!("{}: -c: ", shell), "");
+            Err(if message.is_empty() {
+                format!("{} -n failed ({})", shell, output.status)
+            } else {
+                message.to_string()
+            })
+        }
+        Err(_) => parse(command).map(|_| ()).map_err(|e| e.to_string()),
+    }
+}
+
+/// Whether `text` reads as a shell command rather than a natural language request:
+/// it has to parse, the first program it runs has to exist, and it has to use some
+/// shell syntax. "sort by size" and "make it recursive" start with programs too.
+pub fn looks_like_command(text: &str) -> bool {
+    let Ok(script) = parse(text) else {
+        return false;
+    };
+    let commands = script.simple_commands();
+    let exists = commands
+        .first()
+        .and_then(|command| command.program())
+        .is_some_and(|program| BUILTINS.contains(&program.as_str()) || find_program(&program).is_some());
+    if !exists {
+        return false;
+    }
+
+    let pipelines = script.pipelines();
+    pipelines.len() > 1
+        || pipelines.iter().any(|pipeline| pipeline.commands.len() > 1)
+        || script.items.iter().any(|item| item.background)
+        || commands.iter().any(|command| {
+            !command.assignments.is_empty()
+                || !command.redirects.is_empty()
+                || command.words.iter().skip(1).any(is_shell_word)
+        })
+}
+
+/// A flag, a path, an assignment or an expansion: words that don't turn up in English.
+fn is_shell_word(word: &Word) -> bool {
+    let value = word.value();
+    !word.is_literal()
+        || (value.starts_with('-') && value.len() > 1)
+        || value.contains(['/', '=', '~'])
+        || matches!(value.as_str(), "." | "..")
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Tokens
+// ─────────────────────────────────────────────────────────────────────────────
+
+enum Token {
+    Word(Word),
+    Op(&'static str),
+    Newline,
+    Eof,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word.value()),
+            Token::Op(op) => write!(f, "{}", op),
+            Token::Newline => write!(f, "newline"),
+            Token::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Longest first, so `>>` wins over `>`.
+const OPERATORS: &[&str] = &[
+    "&>>", "<<<", "<<-", "&&", "||", ";;", "|&", "&>", "<<", ">>", ">|", "<&", ">&", "<>", "<", ">", "|", "&",
+    ";", "(", ")",
+];
+
+const REDIRECTS: &[&str] = &["&>>", "<<<", "<<-", "&>", "<<", ">>", ">|", "<&", ">&", "<>", "<", ">"];
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    peeked: Option<Token>,
+    /// Where lexing continues after the current line, past heredoc bodies read ahead
+    heredoc_resume: Option<usize>,
+    /// The file descriptor in front of the redirection operator just lexed
+    fd: Option<u32>,
+}
+
+impl Parser {
+    // ── Grammar ──────────────────────────────────────────────────────────────
+
+    /// Parse and-or lists until end of input, `)`, `;;` or one of the reserved words in `until`.
+    fn script(&mut self, until: &[&str]) -> Result<Script> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines()?;
+            match self.peek()? {
+                Token::Eof | Token::Op(")") | Token::Op(";;") => break,
+                Token::Word(word) if until.iter().any(|w| is_reserved(word, w)) => break,
+                _ => {}
+            }
+
+            let mut item = self.and_or()?;
+            let separated = match self.peek()? {
+                Token::Op("&") => {
+                    item.background = true;
+                    true
+                }
+                Token::Op(";") | Token::Newline => true,
+                _ => false,
+            };
+            items.push(item);
+            if !separated {
+                break;
+            }
+            self.next()?;
+        }
+        Ok(Script { items })
+    }
+
+    fn and_or(&mut self) -> Result<AndOr> {
+        let mut pipelines = vec![self.pipeline()?];
+        let mut operators = Vec::new();
+        while let Token::Op(op @ ("&&" | "||")) = self.peek()? {
+            operators.push(*op);
+            self.next()?;
+            self.skip_newlines()?;
+            pipelines.push(self.pipeline()?);
+        }
+        Ok(AndOr {
+            pipelines,
+            operators,
+            background: false,
+        })
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline> {
+        let negated = self.peek_reserved("!")?;
+        if negated {
+            self.next()?;
+        }
+        let mut commands = vec![self.command()?];
+        let mut operators = Vec::new();
+        while let Token::Op(op @ ("|" | "|&")) = self.peek()? {
+            operators.push(*op);
+            self.next()?;
+            self.skip_newlines()?;
+            commands.push(self.command()?);
+        }
+        Ok(Pipeline {
+            commands,
+            operators,
+            negated,
+        })
+    }
+
+    fn command(&mut self) -> Result<Command> {
+        let (kind, bodies, words) = match self.peek()? {
+            Token::Op("(") => {
+                self.next()?;
+                let body = self.script(&[])?;
+                self.expect_op(")")?;
+                (CompoundKind::Subshell, vec![body], Vec::new())
+            }
+            Token::Word(word) if is_reserved(word, "{") => {
+                self.next()?;
+                let body = self.script(&["}"])?;
+                self.expect_reserved("}")?;
+                (CompoundKind::Group, vec![body], Vec::new())
+            }
+            Token::Word(word) if is_reserved(word, "if") => (CompoundKind::If, self.if_clause()?, Vec::new()),
+            Token::Word(word) if is_reserved(word, "while") || is_reserved(word, "until") => {
+                let kind = if is_reserved(word, "while") { CompoundKind::While } else { CompoundKind::Until };
+                self.next()?;
+                let condition = self.script(&["do"])?;
+                (kind, vec![condition, self.do_group()?], Vec::new())
+            }
+            Token::Word(word) if is_reserved(word, "for") => self.for_clause()?,
+            Token::Word(word) if is_reserved(word, "case") => self.case_clause()?,
+            Token::Word(word) if is_reserved(word, "function") => {
+                self.next()?;
+                let name = self.expect_word()?.value();
+                if matches!(self.peek()?, Token::Op("(")) {
+                    self.next()?;
+                    self.expect_op(")")?;
+                }
+                self.skip_newlines()?;
+                let body = Box::new(self.command()?);
+                return Ok(Command::Function { name, body });
+            }
+            _ => return self.simple_command(),
+        };
+
+        let mut redirects = Vec::new();
+        while let Some(redirect) = self.redirect()? {
+            redirects.push(redirect);
+        }
+        Ok(Command::Compound {
+            kind,
+            bodies,
+            words,
+            redirects,
+        })
+    }
+
+    fn simple_command(&mut self) -> Result<Command> {
+        let mut assignments = Vec::new();
+        let mut words = Vec::new();
+        let mut redirects = Vec::new();
+
+        loop {
+            if let Some(redirect) = self.redirect()? {
+                redirects.push(redirect);
+                continue;
+            }
+            match self.peek()? {
+                Token::Word(_) => {
+                    let Token::Word(word) = self.next()? else {
+                        unreachable!()
+                    };
+                    if words.is_empty() && is_assignment(&word) {
+                        assignments.push(word);
+                    } else if words.is_empty() && is_reserved(&word, "[[") {
+                        words.push(word);
+                        self.test_expression(&mut words)?;
+                    } else {
+                        words.push(word);
+                    }
+                }
+                // name() { …; }
+                Token::Op("(") if words.len() == 1 && assignments.is_empty() && redirects.is_empty() => {
+                    self.next()?;
+                    self.expect_op(")")?;
+                    self.skip_newlines()?;
+                    let name = words.remove(0).value();
+                    let body = Box::new(self.command()?);
+                    return Ok(Command::Function { name, body });
+                }
+                _ => break,
+            }
+        }
+
+        if assignments.is_empty() && words.is_empty() && redirects.is_empty() {
+            let token = self.next()?;
+            bail!("syntax error near unexpected token `{}`", token);
+        }
+        Ok(Command::Simple(SimpleCommand {
+            assignments,
+            words,
+            redirects,
+        }))
+    }
+
+    /// The inside of `[[ … ]]`, where `&&`, `<` and parentheses are operators of the test.
+    fn test_expression(&mut self, words: &mut Vec<Word>) -> Result<()> {
+        loop {
+            match self.next()? {
+                Token::Word(word) => {
+                    let done = is_reserved(&word, "]]");
+                    words.push(word);
+                    if done {
+                        return Ok(());
+                    }
+                }
+                // Kept as words, so the expression prints back the same
+                Token::Op(op) => words.push(Word {
+                    parts: vec![WordPart::Literal {
+                        text: op.to_string(),
+                        quoted: false,
+                    }],
+                }),
+                token => bail!("syntax error: expected `]]` but found `{}`", token),
+            }
+        }
+    }
+
+    fn if_clause(&mut self) -> Result<Vec<Script>> {
+        self.next()?;
+        let mut bodies = vec![self.script(&["then"])?];
+        self.expect_reserved("then")?;
+        bodies.push(self.script(&["elif", "else", "fi"])?);
+        loop {
+            if self.peek_reserved("elif")? {
+                self.next()?;
+                bodies.push(self.script(&["then"])?);
+                self.expect_reserved("then")?;
+                bodies.push(self.script(&["elif", "else", "fi"])?);
+            } else if self.peek_reserved("else")? {
+                self.next()?;
+                bodies.push(self.script(&["fi"])?);
+            } else {
+                self.expect_reserved("fi")?;
+                return Ok(bodies);
+            }
+        }
+    }
+
+    fn for_clause(&mut self) -> Result<(CompoundKind, Vec<Script>, Vec<Word>)> {
+        self.next()?;
+        if matches!(self.peek()?, Token::Op("(")) {
+            bail!("arithmetic for loops are not supported");
+        }
+        let variable = self.expect_word()?.value();
+
+        let mut words = Vec::new();
+        self.skip_newlines()?;
+        let listed = self.peek_reserved("in")?;
+        if listed {
+            self.next()?;
+            while matches!(self.peek()?, Token::Word(_)) {
+                words.push(self.expect_word()?);
+            }
+        }
+        if matches!(self.peek()?, Token::Op(";")) {
+            self.next()?;
+        }
+        Ok((CompoundKind::For { variable, listed }, vec![self.do_group()?], words))
+    }
+
+    fn do_group(&mut self) -> Result<Script> {
+        self.skip_newlines()?;
+        self.expect_reserved("do")?;
+        let body = self.script(&["done"])?;
+        self.expect_reserved("done")?;
+        Ok(body)
+    }
+
+    fn case_clause(&mut self) -> Result<(CompoundKind, Vec<Script>, Vec<Word>)> {
+        self.next()?;
+        let mut words = vec![self.expect_word()?];
+        self.skip_newlines()?;
+        self.expect_reserved("in")?;
+
+        let mut bodies = Vec::new();
+        let mut patterns = Vec::new();
+        loop {
+            self.skip_newlines()?;
+            if self.peek_reserved("esac")? {
+                self.next()?;
+                return Ok((CompoundKind::Case { patterns }, bodies, words));
+            }
+
+            if matches!(self.peek()?, Token::Op("(")) {
+                self.next()?;
+            }
+            words.push(self.expect_word()?);
+            let mut count = 1;
+            while matches!(self.peek()?, Token::Op("|")) {
+                self.next()?;
+                words.push(self.expect_word()?);
+                count += 1;
+            }
+            self.expect_op(")")?;
+            patterns.push(count);
+
+            bodies.push(self.script(&["esac"])?);
+            if matches!(self.peek()?, Token::Op(";;")) {
+                self.next()?;
+            }
+        }
+    }
+
+    fn redirect(&mut self) -> Result<Option<Redirect>> {
+        let op = match self.peek()? {
+            Token::Op(op) if REDIRECTS.contains(op) => *op,
+            _ => return Ok(None),
+        };
+        self.next()?;
+        let fd = self.fd.take();
+
+        let target = match self.next()? {
+            Token::Word(word) => word,
+            token => bail!("syntax error near unexpected token `{}`", token),
+        };
+        let heredoc = match op {
+            "<<" | "<<-" => Some(self.read_heredoc(&target.value(), op == "<<-")),
+            _ => None,
+        };
+        Ok(Some(Redirect {
+            fd,
+            op,
+            target,
+            heredoc,
+        }))
+    }
+
+    // ── Token helpers ────────────────────────────────────────────────────────
+
+    fn peek(&mut self) -> Result<&Token> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lex()?);
+        }
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        match self.peeked.take() {
+            Some(token) => Ok(token),
+            None => self.lex(),
+        }
+    }
+
+    fn peek_reserved(&mut self, reserved: &str) -> Result<bool> {
+        Ok(matches!(self.peek()?, Token::Word(word) if is_reserved(word, reserved)))
+    }
+
+    fn expect_reserved(&mut self, reserved: &str) -> Result<()> {
+        match self.next()? {
+            Token::Word(word) if is_reserved(&word, reserved) => Ok(()),
+            token => bail!("syntax error: expected `{}` but found `{}`", reserved, token),
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        match self.next()? {
+            Token::Op(found) if found == op => Ok(()),
+            token => bail!("syntax error: expected `{}` but found `{}`", op, token),
+        }
+    }
+
+    fn expect_word(&mut self) -> Result<Word> {
+        match self.next()? {
+            Token::Word(word) => Ok(word),
+            token => bail!("syntax error near unexpected token `{}`", token),
+        }
+    }
+
+    fn skip_newlines(&mut self) -> Result<()> {
+        while matches!(self.peek()?, Token::Newline) {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    // ── Lexer ────────────────────────────────────────────────────────────────
+
+    fn char_at(&self, pos: usize) -> Option<char> {
+        self.chars.get(pos).copied()
+    }
+
+    fn lex(&mut self) -> Result<Token> {
+        loop {
+            match self.char_at(self.pos) {
+                Some(' ' | '\t') => self.pos += 1,
+                Some('\\') if self.char_at(self.pos + 1) == Some('\n') => self.pos += 2,
+                Some('#') => {
+                    while self.char_at(self.pos).is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        let Some(ch) = self.char_at(self.pos) else {
+            return Ok(Token::Eof);
+        };
+
+        if ch == '\n' {
+            self.pos += 1;
+            if let Some(resume) = self.heredoc_resume.take() {
+                self.pos = resume;
+            }
+            return Ok(Token::Newline);
+        }
+
+        // A file descriptor number directly before a redirection, as in 2>&1
+        self.fd = None;
+        if ch.is_ascii_digit() {
+            let mut end = self.pos;
+            while self.char_at(end).is_some_and(|c| c.is_ascii_digit()) {
+                end += 1;
+            }
+            if matches!(self.char_at(end), Some('<' | '>')) && self.char_at(end + 1) != Some('(') {
+                self.fd = self.source_between(self.pos, end).parse().ok();
+                self.pos = end;
+            }
+        }
+
+        let is_process_substitution = matches!(self.char_at(self.pos), Some('<' | '>'))
+            && self.char_at(self.pos + 1) == Some('(');
+        if !is_process_substitution
+            && let Some(op) = OPERATORS.iter().find(|op| self.starts_with(op))
+        {
+            self.pos += op.chars().count();
+            return Ok(Token::Op(op));
+        }
+
+        self.word().map(Token::Word)
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars().enumerate().all(|(i, c)| self.char_at(self.pos + i) == Some(c))
+    }
+
+    fn word(&mut self) -> Result<Word> {
+        let mut parts = Vec::new();
+
+        while let Some(ch) = self.char_at(self.pos) {
+            match ch {
+                ' ' | '\t' | '\n' | ';' | '&' | '|' | '(' | ')' => break,
+                '<' | '>' if self.char_at(self.pos + 1) == Some('(') => {
+                    let start = self.pos;
+                    self.pos += 2;
+                    let script = self.nested_script()?;
+                    parts.push(WordPart::Command {
+                        script,
+                        source: self.source(start),
+                        quoted: false,
+                    });
+                }
+                '<' | '>' => break,
+                '\\' => {
+                    self.pos += 1;
+                    match self.char_at(self.pos) {
+                        Some('\n') => self.pos += 1,
+                        Some(c) => {
+                            push_literal(&mut parts, c, true);
+                            self.pos += 1;
+                        }
+                        None => push_literal(&mut parts, '\\', true),
+                    }
+                }
+                '\'' => {
+                    self.pos += 1;
+                    loop {
+                        match self.char_at(self.pos) {
+                            Some('\'') => break,
+                            Some(c) => push_literal(&mut parts, c, true),
+                            None => bail!("unterminated single quote"),
+                        }
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                    // '' is still a (empty) word
+                    if parts.is_empty() {
+                        push_empty(&mut parts);
+                    }
+                }
+                '"' => {
+                    self.pos += 1;
+                    self.double_quoted(&mut parts)?;
+                    if parts.is_empty() {
+                        push_empty(&mut parts);
+                    }
+                }
+                '$' => self.dollar(&mut parts, false)?,
+                '`' => self.backtick(&mut parts, false)?,
+                c => {
+                    push_literal(&mut parts, c, false);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        Ok(Word { parts })
+    }
+
+    fn double_quoted(&mut self, parts: &mut Vec<WordPart>) -> Result<()> {
+        loop {
+            match self.char_at(self.pos) {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some('\\') => {
+                    match self.char_at(self.pos + 1) {
+                        Some('\n') => {}
+                        Some(c @ ('$' | '`' | '"' | '\\')) => push_literal(parts, c, true),
+                        Some(c) => {
+                            push_literal(parts, '\\', true);
+                            push_literal(parts, c, true);
+                        }
+                        None => bail!("unterminated double quote"),
+                    }
+                    self.pos += 2;
+                }
+                Some('$') => self.dollar(parts, true)?,
+                Some('`') => self.backtick(parts, true)?,
+                Some(c) => {
+                    push_literal(parts, c, true);
+                    self.pos += 1;
+                }
+                None => bail!("unterminated double quote"),
+            }
+        }
+    }
+
+    fn dollar(&mut self, parts: &mut Vec<WordPart>, quoted: bool) -> Result<()> {
+        let start = self.pos;
+        match self.char_at(self.pos + 1) {
+            Some('(') if self.char_at(self.pos + 2) == Some('(') => {
+                self.pos += 3;
+                self.skip_balanced('(', ')', 2)?;
+                parts.push(WordPart::Arithmetic(self.source(start)));
+            }
+            Some('(') => {
+                self.pos += 2;
+                let script = self.nested_script()?;
+                parts.push(WordPart::Command {
+                    script,
+                    source: self.source(start),
+                    quoted,
+                });
+            }
+            Some('{') => {
+                self.pos += 2;
+                self.skip_balanced('{', '}', 1)?;
+                parts.push(WordPart::Param {
+                    text: self.source(start),
+                    quoted,
+                });
+            }
+            Some('\'') if !quoted => {
+                // $'…' with C-style escapes
+                self.pos += 2;
+                loop {
+                    match self.char_at(self.pos) {
+                        Some('\'') => break,
+                        Some('\\') => {
+                            let decoded = match self.char_at(self.pos + 1) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('r') => '\r',
+                                Some('e') => '\x1b',
+                                Some(c) => c,
+                                None => bail!("unterminated $' quote"),
+                            };
+                            push_literal(parts, decoded, true);
+                            self.pos += 1;
+                        }
+                        Some(c) => push_literal(parts, c, true),
+                        None => bail!("unterminated $' quote"),
+                    }
+                    self.pos += 1;
+                }
+                self.pos += 1;
+                if parts.is_empty() {
+                    push_empty(parts);
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                self.pos += 1;
+                while self.char_at(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                parts.push(WordPart::Param {
+                    text: self.source(start),
+                    quoted,
+                });
+            }
+            Some(c) if "@*#?$!-".contains(c) || c.is_ascii_digit() => {
+                self.pos += 2;
+                parts.push(WordPart::Param {
+                    text: self.source(start),
+                    quoted,
+                });
+            }
+            _ => {
+                push_literal(parts, '$', quoted);
+                self.pos += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn backtick(&mut self, parts: &mut Vec<WordPart>, quoted: bool) -> Result<()> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut inner = String::new();
+        loop {
+            match self.char_at(self.pos) {
+                Some('`') => break,
+                Some('\\') if matches!(self.char_at(self.pos + 1), Some('`' | '\\' | '$')) => {
+                    inner.push(self.chars[self.pos + 1]);
+                    self.pos += 2;
+                    continue;
+                }
+                Some(c) => inner.push(c),
+                None => bail!("unterminated backquote"),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        parts.push(WordPart::Command {
+            script: parse(&inner)?,
+            source: self.source(start),
+            quoted,
+        });
+        Ok(())
+    }
+
+    /// Parse the body of `$(…)` or `<(…)`, up to and including the closing parenthesis.
+    fn nested_script(&mut self) -> Result<Script> {
+        let script = self.script(&[])?;
+        match self.next()? {
+            Token::Op(")") => Ok(script),
+            Token::Eof => bail!("unterminated command substitution"),
+            token => bail!("syntax error near unexpected token `{}`", token),
+        }
+    }
+
+    /// Skip to just past the `close` that balances `depth` already open delimiters.
+    fn skip_balanced(&mut self, open: char, close: char, mut depth: usize) -> Result<()> {
+        while let Some(c) = self.char_at(self.pos) {
+            self.pos += 1;
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(());
+                }
+            }
+        }
+        bail!("missing `{}`", close)
+    }
+
+    fn source(&self, start: usize) -> String {
+        self.source_between(start, self.pos)
+    }
+
+    fn source_between(&self, start: usize, end: usize) -> String {
+        self.chars[start..end].iter().collect()
+    }
+
+    /// Read the body of a heredoc that starts on the line after the current one (or
+    /// after the previous heredoc on the same line).
+    fn read_heredoc(&mut self, delimiter: &str, strip_tabs: bool) -> String {
+        let mut pos = match self.heredoc_resume {
+            Some(pos) => pos,
+            None => match self.chars[self.pos..].iter().position(|&c| c == '\n') {
+                Some(offset) => self.pos + offset + 1,
+                None => self.chars.len(),
+            },
+        };
+
+        let mut body = String::new();
+        while pos < self.chars.len() {
+            let end = self.chars[pos..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(self.chars.len(), |offset| pos + offset);
+            let line: String = self.chars[pos..end].iter().collect();
+            pos = (end + 1).min(self.chars.len());
+
+            let line = if strip_tabs { line.trim_start_matches('\t') } else { line.as_str() };
+            if line == delimiter {
+                break;
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+
+        self.heredoc_resume = Some(pos);
+        body
+    }
+}
+
+fn push_literal(parts: &mut Vec<WordPart>, c: char, quoted: bool) {
+    match parts.last_mut() {
+        Some(WordPart::Literal { text, quoted: last }) if *last == quoted => text.push(c),
+        _ => parts.push(WordPart::Literal {
+            text: c.to_string(),
+            quoted,
+        }),
+    }
+}
+
+fn push_empty(parts: &mut Vec<WordPart>) {
+    parts.push(WordPart::Literal {
+        text: String::new(),
+        quoted: true,
+    });
+}
+
+/// Reserved words only count when nothing in them is quoted.
+fn is_reserved(word: &Word, reserved: &str) -> bool {
+    matches!(word.parts.as_slice(), [WordPart::Literal { text, quoted: false }] if text == reserved)
+}
+
+fn is_assignment(word: &Word) -> bool {
+    let Some(WordPart::Literal { text, quoted: false }) = word.parts.first() else {
+        return false;
+    };
+    text.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Printing
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Everything goes on one line joined with `;`, except heredoc bodies, which follow
+// the line that uses them. Quoting is redone from scratch: quoted text ends up in
+// double quotes, and expansions and substitutions are printed as written.
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut printer = Printer::default();
+        printer.script(self, false);
+        f.write_str(printer.out.trim_end_matches('\n'))
+    }
+}
+
+#[derive(Default)]
+struct Printer<'a> {
+    out: String,
+    /// Heredocs whose bodies go after the end of the current line
+    heredocs: Vec<&'a Redirect>,
+}
+
+impl<'a> Printer<'a> {
+    /// `terminate` ends the last item with `;` as well, which `{ …; }` and `then …; fi` need.
+    fn script(&mut self, script: &'a Script, terminate: bool) {
+        for (index, item) in script.items.iter().enumerate() {
+            self.space();
+            self.and_or(item);
+            if item.background {
+                self.out.push_str(" &");
+            } else if terminate || index + 1 < script.items.len() {
+                self.out.push(';');
+            }
+            self.heredoc_bodies();
+        }
+    }
+
+    fn and_or(&mut self, and_or: &'a AndOr) {
+        for (index, pipeline) in and_or.pipelines.iter().enumerate() {
+            if index > 0 {
+                self.space();
+                self.out.push_str(and_or.operators[index - 1]);
+                self.space();
+            }
+            self.pipeline(pipeline);
+        }
+    }
+
+    fn pipeline(&mut self, pipeline: &'a Pipeline) {
+        if pipeline.negated {
+            self.out.push_str("! ");
+        }
+        for (index, command) in pipeline.commands.iter().enumerate() {
+            if index > 0 {
+                self.space();
+                self.out.push_str(pipeline.operators[index - 1]);
+                self.space();
+            }
+            self.command(command);
+        }
+    }
+
+    fn command(&mut self, command: &'a Command) {
+        match command {
+            Command::Simple(simple) => {
+                for word in simple.assignments.iter().chain(&simple.words) {
+                    self.space();
+                    self.word(word);
+                }
+                self.redirects(&simple.redirects);
+            }
+            Command::Compound {
+                kind,
+                bodies,
+                words,
+                redirects,
+            } => {
+                self.compound(kind, bodies, words);
+                self.redirects(redirects);
+            }
+            Command::Function { name, body } => {
+                self.out.push_str(name);
+                self.out.push_str("()");
+                self.space();
+                self.command(body);
+            }
+        }
+    }
+
+    fn compound(&mut self, kind: &'a CompoundKind, bodies: &'a [Script], words: &'a [Word]) {
+        match kind {
+            CompoundKind::Subshell => {
+                self.keyword("(");
+                self.script(&bodies[0], false);
+                self.keyword(")");
+            }
+            CompoundKind::Group => {
+                self.keyword("{");
+                self.script(&bodies[0], true);
+                self.keyword("}");
+            }
+            CompoundKind::If => {
+                for (index, pair) in bodies.chunks(2).enumerate() {
+                    match pair {
+                        [condition, branch] => {
+                            self.keyword(if index == 0 { "if" } else { "elif" });
+                            self.script(condition, true);
+                            self.keyword("then");
+                            self.script(branch, true);
+                        }
+                        [otherwise] => {
+                            self.keyword("else");
+                            self.script(otherwise, true);
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                self.keyword("fi");
+            }
+            CompoundKind::While | CompoundKind::Until => {
+                self.keyword(if *kind == CompoundKind::While { "while" } else { "until" });
+                self.script(&bodies[0], true);
+                self.do_group(&bodies[1]);
+            }
+            CompoundKind::For { variable, listed } => {
+                self.keyword("for");
+                self.keyword(variable);
+                if *listed {
+                    self.keyword("in");
+                    for word in words {
+                        self.space();
+                        self.word(word);
+                    }
+                }
+                self.out.push(';');
+                self.do_group(&bodies[0]);
+            }
+            CompoundKind::Case { patterns } => {
+                self.keyword("case");
+                self.space();
+                self.word(&words[0]);
+                self.keyword("in");
+                let mut words = words[1..].iter();
+                for (body, &count) in bodies.iter().zip(patterns) {
+                    self.space();
+                    for (index, pattern) in words.by_ref().take(count).enumerate() {
+                        if index > 0 {
+                            self.out.push_str(" | ");
+                        }
+                        self.word(pattern);
+                    }
+                    self.out.push(')');
+                    self.script(body, false);
+                    self.keyword(";;");
+                }
+                self.keyword("esac");
+            }
+        }
+    }
+
+    fn do_group(&mut self, body: &'a Script) {
+        self.keyword("do");
+        self.script(body, true);
+        self.keyword("done");
+    }
+
+    fn redirects(&mut self, redirects: &'a [Redirect]) {
+        for redirect in redirects {
+            self.space();
+            if let Some(fd) = redirect.fd {
+                self.out.push_str(&fd.to_string());
+            }
+            self.out.push_str(redirect.op);
+            let target = word_source(&redirect.target);
+            // > >(tee log) must not turn into >>(tee log)
+            if target.starts_with(['<', '>']) {
+                self.out.push(' ');
+            }
+            self.out.push_str(&target);
+            if redirect.heredoc.is_some() {
+                self.heredocs.push(redirect);
+            }
+        }
+    }
+
+    fn heredoc_bodies(&mut self) {
+        for redirect in self.heredocs.drain(..) {
+            if !self.out.ends_with('\n') {
+                self.out.push('\n');
+            }
+            self.out.push_str(redirect.heredoc.as_deref().unwrap_or_default());
+            self.out.push_str(&redirect.target.value());
+            self.out.push('\n');
+        }
+    }
+
+    fn word(&mut self, word: &Word) {
+        self.out.push_str(&word_source(word));
+    }
+
+    fn keyword(&mut self, keyword: &str) {
+        self.space();
+        self.out.push_str(keyword);
+    }
+
+    /// Separate what comes next from what came before, unless a line just ended.
+    fn space(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with([' ', '\n']) {
+            self.out.push(' ');
+        }
+    }
+}
+
+/// `word` as source text. Quoted parts share double quotes; the rest is printed as is,
+/// since unquoted text can't contain anything the lexer would split on.
+fn word_source(word: &Word) -> String {
+    let mut out = String::new();
+    let mut in_quotes = false;
+    // Whether the last part was a bare `$name`, which text in the same quotes would extend
+    let mut after_name = false;
+
+    for part in &word.parts {
+        let (quoted, text) = match part {
+            WordPart::Literal { text, quoted } => (*quoted, text),
+            WordPart::Param { text, quoted } => (*quoted, text),
+            WordPart::Command { source, quoted, .. } => (*quoted, source),
+            WordPart::Arithmetic(text) => (in_quotes, text),
+        };
+        let extends_name = after_name
+            && matches!(part, WordPart::Literal { text, .. } if text.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_'));
+        if in_quotes && (!quoted || extends_name) {
+            out.push('"');
+            in_quotes = false;
+        }
+        if quoted && !in_quotes {
+            out.push('"');
+            in_quotes = true;
+        }
+
+        match part {
+            WordPart::Literal { quoted: true, .. } => {
+                for c in text.chars() {
+                    if matches!(c, '$' | '`' | '"' | '\\') {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+            }
+            _ => out.push_str(text),
+        }
+        after_name = matches!(part, WordPart::Param { text, .. } if !text.starts_with("${"));
+    }
+
+    if in_quotes {
+        out.push('"');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse, print and parse again; the second tree has to match the first.
+    fn round_trip(source: &str) -> Script {
+        let script = parse(source).unwrap();
+        let printed = script.to_string();
+        let reparsed = parse(&printed).unwrap_or_else(|e| panic!("{:?} printed as {:?}: {}", source, printed, e));
+        assert_eq!(reparsed, script, "{:?} printed as {:?}", source, printed);
+        script
+    }
+
+    fn argv(script: &Script) -> Vec<Vec<String>> {
+        script.simple_commands().iter().map(|command| command.argv()).collect()
+    }
+
+    #[test]
+    fn nested_quotes() {
+        let script = round_trip(r#"echo "it's" 'say "hi"' "a \"b\" \\ c" 'x'\''y' "$HOME"'/$HOME'"#);
+        assert_eq!(
+            argv(&script)[0],
+            ["echo", "it's", r#"say "hi""#, r#"a "b" \ c"#, "x'y", "$HOME/$HOME"]
+        );
+        // The second $HOME was single quoted, so it stays text
+        let home = &script.simple_commands()[0].words[5];
+        assert!(matches!(&home.parts[1], WordPart::Literal { text, quoted: true } if text == "/$HOME"));
+    }
+
+    #[test]
+    fn ansi_c_quotes() {
+        let script = round_trip(r"printf $'a\tb\n' $'it\'s' x$'\e[0m'");
+        assert_eq!(argv(&script)[0], ["printf", "a\tb\n", "it's", "x\x1b[0m"]);
+    }
+
+    #[test]
+    fn heredocs() {
+        let script = round_trip("cat <<EOF | grep x && cat <<-'END' 2>&1\nhello $USER\nEOF\n\tbye\n\tEND\necho done");
+        let heredocs: Vec<_> = script
+            .simple_commands()
+            .iter()
+            .flat_map(|command| &command.redirects)
+            .filter_map(|redirect| redirect.heredoc.as_deref())
+            .collect();
+        assert_eq!(heredocs, ["hello $USER\n", "bye\n"]);
+        assert_eq!(argv(&script)[3], ["echo", "done"]);
+
+        round_trip("if true; then\n  cat <<EOF\n  inside\nEOF\nfi; sh <<\\X\nrm -rf /\nX");
+    }
+
+    #[test]
+    fn command_substitution_in_double_quotes() {
+        let script = round_trip(r#"echo "today is $(date +%F) in $(pwd | sed "s/a/b/")" "$(ls)"x"#);
+        let word = &script.simple_commands()[0].words[1];
+        assert!(matches!(&word.parts[1], WordPart::Command { quoted: true, .. }));
+        assert_eq!(argv(&script)[1..], [vec!["date", "+%F"], vec!["pwd"], vec!["sed", "s/a/b/"], vec!["ls"]]);
+    }
+
+    #[test]
+    fn escaped_backticks() {
+        let script = round_trip(r#"echo \`not run\` "\`also not\`" `echo \`date\``"#);
+        assert_eq!(argv(&script)[0][..4], ["echo", "`not", "run`", "`also not`"]);
+        assert_eq!(argv(&script)[1..], [vec!["echo", "`date`"], vec!["date"]]);
+    }
+
+    #[test]
+    fn parameter_expansions() {
+        let script = round_trip(r#"echo ${x:-a b} "${y:-c d}" $z"_1" "$z"_2 ${#arr[@]}"#);
+        assert_eq!(argv(&script)[0], ["echo", "${x:-a b}", "${y:-c d}", "$z_1", "$z_2", "${#arr[@]}"]);
+    }
+
+    #[test]
+    fn compound_commands() {
+        round_trip("if a; then b; elif c; then d; else e; fi && for f in 1 '2 3'; do g & done || while h; do :; done");
+        round_trip("for arg\ndo echo \"$arg\"; done; until false; do break; done");
+        round_trip("case $1 in a|b) x;; *.txt) y; z;; (c) ;; esac");
+        round_trip("f() { local a=1; } ; function g { ( (h) ) > /dev/null; }");
+        round_trip("! a |& b 2>&1 >out 3<in; { c; } >>log & [[ -n $d && $e == f* ]]");
+        round_trip("diff <(sort a) <(sort b) > >(tee log); x=1 y=\"2 3\" env");
+    }
+
+    #[test]
+    fn quoted_reserved_words_are_plain_words() {
+        let script = round_trip(r#""if" x; 'for' y"#);
+        assert_eq!(argv(&script), [["if", "x"], ["for", "y"]]);
+    }
+
+    #[test]
+    fn commands_need_shell_syntax() {
+        for text in ["test it first", "cd into it", "echo it too", "export it as csv"] {
+            assert!(!looks_like_command(text), "{:?}", text);
+        }
+        for text in ["test -f Cargo.toml", "cd ..", "echo $HOME", "echo hi > out", "export A=1", "cd src && pwd"] {
+            assert!(looks_like_command(text), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn prints_one_line() {
+        let script = parse("echo   'a b'  c\necho ~/*.txt|wc -l # count").unwrap();
+        assert_eq!(script.to_string(), r#"echo "a b" c; echo ~/*.txt | wc -l"#);
+    }
+}
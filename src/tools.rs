@@ -0,0 +1,76 @@
+use std::env;
+
+use crate::shell::{self, Command, SimpleCommand, BUILTINS};
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Checking that the programs a command runs are installed
+// ─────────────────────────────────────────────────────────────────────────────
+
+/// Programs in `command` that are neither on PATH nor builtins, aliases or functions.
+///
+/// Aliases and functions come from `WTF_SHELL_NAMES`, which the shell integration
+/// fills in; without it only PATH and builtins are known.
+pub fn missing_programs(command: &str) -> Vec<String> {
+    let Ok(script) = shell::parse(command) else {
+        return Vec::new();
+    };
+
+    let shell_names = env::var("WTF_SHELL_NAMES").unwrap_or_default();
+    let shell_names: Vec<&str> = shell_names.split_whitespace().collect();
+
+    // Functions the command defines for itself
+    let defined: Vec<&str> = script
+        .pipelines()
+        .into_iter()
+        .flat_map(|pipeline| &pipeline.commands)
+        .filter_map(|command| match command {
+            Command::Function { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    let commands = script.simple_commands();
+    let mut missing: Vec<String> = Vec::new();
+    for program in commands.iter().flat_map(|command| invoked_programs(command)) {
+        let known = BUILTINS.contains(&program.as_str())
+            || defined.contains(&program.as_str())
+            || shell_names.contains(&program.as_str())
+            || missing.contains(&program)
+            || shell::find_program(&program).is_some();
+        if !known {
+            missing.push(program);
+        }
+    }
+
+    // `brew install fd && fd …` is fine
+    missing.retain(|program| {
+        !commands.iter().any(|command| {
+            let argv = command.argv();
+            argv.iter().any(|arg| arg == "install") && argv.contains(program)
+        })
+    });
+    missing
+}
+
+/// The program a simple command runs, followed by whatever wrappers such as
+/// `sudo` or `xargs` run in turn.
+fn invoked_programs(command: &SimpleCommand) -> Vec<String> {
+    let argv = command.argv();
+    let mut programs = Vec::new();
+    let mut words = &argv[..];
+
+    while let Some((program, args)) = words.split_first() {
+        // Programs picked by an expansion can't be checked
+        if !command.words[argv.len() - words.len()].is_literal() {
+            break;
+        }
+        programs.push(program.clone());
+
+        let Some(wrapped) = shell::wrapped_command(program, args) else {
+            break;
+        };
+        words = wrapped;
+    }
+
+    programs
+}
@@ -0,0 +1,241 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Summary of the current directory, for the system prompt
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// "run the tests" means `just test` in one repository and `npm test` in the next.
+// This tells the model what is here, within a fixed budget so a huge directory
+// can't crowd out the request.
+
+/// Upper bound on the rendered section, in bytes.
+const BUDGET: usize = 1500;
+
+const FOOTER: &str = "Use this project's own scripts and file names when they fit the request.\n";
+
+/// Most names listed from any one project file (scripts, targets, services).
+const MAX_NAMES: usize = 15;
+
+/// Files that say how a project is built and run, and how to read their task names.
+const PROJECT_FILES: &[(&str, Tasks)] = &[
+    ("Cargo.toml", Tasks::None),
+    ("package.json", Tasks::NpmScripts),
+    ("Makefile", Tasks::MakeTargets),
+    ("makefile", Tasks::MakeTargets),
+    ("GNUmakefile", Tasks::MakeTargets),
+    ("justfile", Tasks::JustRecipes),
+    ("Justfile", Tasks::JustRecipes),
+    ("docker-compose.yml", Tasks::ComposeServices),
+    ("docker-compose.yaml", Tasks::ComposeServices),
+    ("compose.yml", Tasks::ComposeServices),
+    ("compose.yaml", Tasks::ComposeServices),
+    ("Dockerfile", Tasks::None),
+    ("pyproject.toml", Tasks::None),
+    ("requirements.txt", Tasks::None),
+    ("go.mod", Tasks::None),
+    ("Gemfile", Tasks::None),
+    ("pom.xml", Tasks::None),
+    ("build.gradle", Tasks::None),
+    ("build.gradle.kts", Tasks::None),
+    ("CMakeLists.txt", Tasks::None),
+    ("Taskfile.yml", Tasks::None),
+];
+
+#[derive(Clone, Copy)]
+enum Tasks {
+    None,
+    NpmScripts,
+    MakeTargets,
+    JustRecipes,
+    ComposeServices,
+}
+
+pub struct Workspace {
+    /// Rendered prompt section, already within budget
+    section: String,
+}
+
+impl Workspace {
+    pub fn collect(dir: &Path) -> Self {
+        let mut header = format!("\nThe current directory ({}):\n", dir.display());
+
+        for (name, tasks) in PROJECT_FILES {
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            let names = fs::read_to_string(&path).map(|content| task_names(*tasks, &content)).unwrap_or_default();
+            if names.is_empty() {
+                header.push_str(&format!("- {}\n", name));
+            } else {
+                let label = match tasks {
+                    Tasks::NpmScripts => "scripts",
+                    Tasks::MakeTargets => "targets",
+                    Tasks::JustRecipes => "recipes",
+                    Tasks::ComposeServices => "services",
+                    Tasks::None => "",
+                };
+                header.push_str(&format!("- {} ({}: {})\n", name, label, names.join(", ")));
+            }
+        }
+
+        if let Some(git) = git_state(dir) {
+            header.push_str(&format!("- git: {}\n", git));
+        }
+
+        let budget = BUDGET - FOOTER.len();
+        let mut section = truncate(&header, budget);
+        let entries = list_entries(dir);
+        let prefix = "- Files: ";
+        if !entries.is_empty() && section.len() + prefix.len() + 1 < budget {
+            section.push_str(prefix);
+            let room = budget - section.len() - 1;
+            section.push_str(&join_within(&entries, room));
+            section.push('\n');
+        }
+        section.push_str(FOOTER);
+
+        Workspace { section }
+    }
+
+    pub fn prompt_section(&self) -> &str {
+        &self.section
+    }
+}
+
+/// Top-level entries, directories first and marked with a slash, hidden ones left out.
+fn list_entries(dir: &Path) -> Vec<String> {
+    let Ok(read) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<(bool, String)> = read
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                return None;
+            }
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            Some((is_dir, name))
+        })
+        .collect();
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    entries
+        .into_iter()
+        .map(|(is_dir, name)| if is_dir { format!("{}/", name) } else { name })
+        .collect()
+}
+
+fn task_names(tasks: Tasks, content: &str) -> Vec<String> {
+    let mut names: Vec<String> = match tasks {
+        Tasks::None => Vec::new(),
+        Tasks::NpmScripts => serde_json::from_str::<serde_json::Value>(content)
+            .ok()
+            .and_then(|package| {
+                package
+                    .get("scripts")
+                    .and_then(|scripts| scripts.as_object())
+                    .map(|scripts| scripts.keys().cloned().collect())
+            })
+            .unwrap_or_default(),
+        // `name:` at the start of a line, leaving out variables and special targets
+        Tasks::MakeTargets | Tasks::JustRecipes => content
+            .lines()
+            .filter(|line| !line.starts_with([' ', '\t', '.', '#']))
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, rest)| !name.contains('=') && !rest.starts_with('='))
+            .filter_map(|(name, _)| name.split_whitespace().next())
+            .filter(|name| !name.contains('$') && !name.contains('%'))
+            .map(str::to_string)
+            .collect(),
+        // Keys indented one level under `services:`
+        Tasks::ComposeServices => {
+            let mut in_services = false;
+            let mut names = Vec::new();
+            for line in content.lines() {
+                if !line.starts_with([' ', '\t']) {
+                    in_services = line.trim_end() == "services:";
+                    continue;
+                }
+                let indent = line.len() - line.trim_start().len();
+                if in_services
+                    && indent <= 4
+                    && let Some(name) = line.trim().strip_suffix(':')
+                    && !name.starts_with('#')
+                {
+                    names.push(name.to_string());
+                }
+            }
+            names
+        }
+    };
+    names.dedup();
+    if names.len() > MAX_NAMES {
+        let more = names.len() - MAX_NAMES;
+        names.truncate(MAX_NAMES);
+        names.push(format!("… {} more", more));
+    }
+    names
+}
+
+/// "branch main, 3 uncommitted changes", or `None` outside a repository.
+fn git_state(dir: &Path) -> Option<String> {
+    // symbolic-ref also works before the first commit; rev-parse covers a detached HEAD
+    let branch = git(dir, &["symbolic-ref", "--short", "HEAD"])
+        .or_else(|| git(dir, &["rev-parse", "--short", "HEAD"]).map(|commit| format!("(detached at {})", commit.trim())))?;
+    let status = git(dir, &["status", "--porcelain"]).unwrap_or_default();
+    let changes = status.lines().count();
+    Some(match changes {
+        0 => format!("branch {}, clean", branch.trim()),
+        n => format!("branch {}, {} uncommitted change{}", branch.trim(), n, if n == 1 { "" } else { "s" }),
+    })
+}
+
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// As many of `names` as fit in `room` bytes, with a count of the rest.
+fn join_within(names: &[String], room: usize) -> String {
+    let mut joined = String::new();
+    for (index, name) in names.iter().enumerate() {
+        let rest = format!(" … {} more", names.len() - index);
+        let separator = if joined.is_empty() { "" } else { ", " };
+        let fits_with_rest = joined.len() + separator.len() + name.len() + rest.len() <= room;
+        let is_last = index + 1 == names.len();
+        if fits_with_rest || (is_last && joined.len() + separator.len() + name.len() <= room) {
+            joined.push_str(separator);
+            joined.push_str(name);
+        } else {
+            joined.push_str(&rest);
+            break;
+        }
+    }
+    joined
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    // Keep whole lines
+    let cut = text[..end].rfind('\n').map_or(end, |newline| newline + 1);
+    text[..cut].to_string()
+}